@@ -0,0 +1,144 @@
+use std::path::Path;
+
+use noodles::sam::record::quality_scores::Score;
+
+const MAX_QUALITY: usize = 93;
+
+// Illumina's 8-level binning, as used by HiSeq X and later.
+const ILLUMINA_8: &str = "2-9:6,10-19:15,20-24:22,25-29:27,30-34:33,35-39:37,40-93:40";
+
+// NovaSeq's 4-level binning.
+const ILLUMINA_4: &str = "0-2:2,3-14:12,15-30:23,31-93:37";
+
+// A map from each quality to its bin. Qualities the table doesn't mention are left as they are.
+#[derive(Clone, Copy, Debug)]
+pub struct QualityBins {
+    table: [u8; MAX_QUALITY + 1],
+}
+
+impl QualityBins {
+    // illumina8, illumina4, or a table like 0-9:6,10-19:15,20-93:37, given inline or in a file.
+    pub fn parse(txt: &str) -> std::io::Result<QualityBins> {
+        match txt {
+            "illumina8" => QualityBins::from_table(ILLUMINA_8),
+            "illumina4" => QualityBins::from_table(ILLUMINA_4),
+            _ if Path::new(txt).is_file() => {
+                QualityBins::from_table(&std::fs::read_to_string(txt)?)
+            }
+            _ => QualityBins::from_table(txt),
+        }
+    }
+
+    fn from_table(txt: &str) -> std::io::Result<QualityBins> {
+        let invalid = |entry: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("invalid quality bin: {}", entry),
+            )
+        };
+        let quality = |txt: &str| -> Option<u8> {
+            txt.trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|q| *q as usize <= MAX_QUALITY)
+        };
+        let mut table = [0; MAX_QUALITY + 1];
+        for (q, bin) in table.iter_mut().enumerate() {
+            *bin = q as u8;
+        }
+        let mut binned = [false; MAX_QUALITY + 1];
+        for entry in txt.split([',', '\n']) {
+            if entry.trim().is_empty() {
+                continue;
+            }
+            let (range, value) = entry.split_once(':').ok_or_else(|| invalid(entry))?;
+            let (lo, hi) = range.split_once('-').unwrap_or((range, range));
+            let lo = quality(lo).ok_or_else(|| invalid(entry))?;
+            let hi = quality(hi).ok_or_else(|| invalid(entry))?;
+            let value = quality(value).ok_or_else(|| invalid(entry))?;
+            // Ranges run upwards and each quality is given one bin.
+            let range = lo as usize..=hi as usize;
+            if lo > hi || binned[range.clone()].contains(&true) {
+                return Err(invalid(entry));
+            }
+            for q in range {
+                table[q] = value;
+                binned[q] = true;
+            }
+        }
+        Ok(QualityBins { table })
+    }
+
+    pub fn bin(&self, score: Score) -> Score {
+        Score::new(self.table[score.get() as usize]).unwrap_or(score)
+    }
+
+    pub fn changed(&self, scores: &[Score]) -> usize {
+        scores.iter().filter(|q| self.bin(**q) != **q).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scores(qs: &[u8]) -> Vec<Score> {
+        qs.iter().map(|q| Score::new(*q).unwrap()).collect()
+    }
+
+    fn binned(bins: &QualityBins, qs: &[u8]) -> Vec<u8> {
+        scores(qs).into_iter().map(|q| bins.bin(q).get()).collect()
+    }
+
+    #[test]
+    fn illumina_8() {
+        let bins = QualityBins::parse("illumina8").unwrap();
+        assert_eq!(
+            binned(
+                &bins,
+                &[0, 1, 2, 9, 10, 19, 20, 24, 25, 29, 30, 34, 35, 39, 40, 93]
+            ),
+            vec![0, 1, 6, 6, 15, 15, 22, 22, 27, 27, 33, 33, 37, 37, 40, 40]
+        );
+    }
+
+    #[test]
+    fn illumina_4() {
+        let bins = QualityBins::parse("illumina4").unwrap();
+        assert_eq!(
+            binned(&bins, &[0, 2, 3, 14, 15, 30, 31, 93]),
+            vec![2, 2, 12, 12, 23, 23, 37, 37]
+        );
+    }
+
+    #[test]
+    fn custom_table() {
+        let bins = QualityBins::parse("0-9:6, 10-19:15\n25:30").unwrap();
+        assert_eq!(
+            binned(&bins, &[0, 9, 10, 19, 20, 25, 26]),
+            vec![6, 6, 15, 15, 20, 30, 26]
+        );
+    }
+
+    #[test]
+    fn malformed_tables() {
+        for table in [
+            "30-10:20",
+            "0-20:10,15-30:25",
+            "5:8,5:9",
+            "0-94:40",
+            "0-9:94",
+            "10",
+            "a-b:c",
+        ] {
+            assert!(QualityBins::parse(table).is_err(), "{}", table);
+        }
+    }
+
+    #[test]
+    fn changed_bases() {
+        let bins = QualityBins::parse("illumina8").unwrap();
+        assert_eq!(bins.changed(&scores(&[2, 6, 15, 16, 40, 41])), 3);
+        assert_eq!(bins.changed(&[]), 0);
+    }
+}
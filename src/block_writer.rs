@@ -1,59 +1,605 @@
 use std::{
-    fs::File,
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
     io::{Read, Write},
-    sync::mpsc::{sync_channel, SyncSender},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
     thread::JoinHandle,
 };
 
-use flate2::{bufread::GzEncoder, Compression};
+use flate2::{
+    bufread::{GzEncoder, MultiGzDecoder},
+    Compression,
+};
 
 pub struct DataBlock {
-    id: String,
-    data: Vec<u8>,
+    pub(crate) id: String,
+    pub(crate) data: Vec<u8>,
+}
+
+// Where a pair ends in the R1, R2 and sidecar data of a block.
+pub type PairEnd = (usize, usize, usize);
+
+// When chunks are limited, pairs are encoded in runs of about this many bytes, which is as
+// finely as a block can be split without encoding any of it again.
+const SEGMENT_SIZE: usize = 256 * 1024;
+
+type EncodedPairs = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+pub struct BlockPair {
+    key: String,
+    blocks: (DataBlock, DataBlock),
+    sidecar: Vec<u8>,
+    segments: Vec<Segment>,
+    compression: Option<Compression>,
+}
+
+// A run of a block's pairs that was encoded on its own: how many there are, the bytes they
+// take in each file, and where each pair ends before encoding.
+struct Segment {
+    count: usize,
+    sizes: (usize, usize, usize),
+    ends: Vec<PairEnd>,
+}
+
+// Encodes the pairs in runs of about segment_size bytes, each of them on its own.
+fn encode_segments(
+    blocks: (&[u8], &[u8], &[u8]),
+    ends: &[PairEnd],
+    segment_size: usize,
+    compression: Option<Compression>,
+) -> std::io::Result<(EncodedPairs, Vec<Segment>)> {
+    let mut data: EncodedPairs = (Vec::new(), Vec::new(), Vec::new());
+    let mut segments = Vec::new();
+    let mut first = 0;
+    let mut from = (0, 0, 0);
+    for (i, end) in ends.iter().enumerate() {
+        if i + 1 < ends.len() && (end.0 - from.0) + (end.1 - from.1) < segment_size {
+            continue;
+        }
+        let encoded = (
+            encode_block(&blocks.0[from.0..end.0], compression)?,
+            encode_block(&blocks.1[from.1..end.1], compression)?,
+            encode_block(&blocks.2[from.2..end.2], compression)?,
+        );
+        segments.push(Segment {
+            count: i + 1 - first,
+            sizes: (encoded.0.len(), encoded.1.len(), encoded.2.len()),
+            ends: ends[first..=i]
+                .iter()
+                .map(|e| (e.0 - from.0, e.1 - from.1, e.2 - from.2))
+                .collect(),
+        });
+        data.0.extend(encoded.0);
+        data.1.extend(encoded.1);
+        data.2.extend(encoded.2);
+        first = i + 1;
+        from = *end;
+    }
+    Ok((data, segments))
+}
+
+fn decode_block(data: &[u8], compression: Option<Compression>) -> std::io::Result<Vec<u8>> {
+    match compression {
+        Some(_) if !data.is_empty() => {
+            let mut result = Vec::new();
+            MultiGzDecoder::new(data).read_to_end(&mut result)?;
+            Ok(result)
+        }
+        _ => Ok(Vec::from(data)),
+    }
+}
+
+type PieceData<'a> = (Cow<'a, [u8]>, Cow<'a, [u8]>, Cow<'a, [u8]>);
+
+// Encoded pairs on their way to a chunk: a segment as it came, or part of one.
+struct Piece<'a> {
+    count: usize,
+    data: PieceData<'a>,
+    ends: Cow<'a, [PairEnd]>,
+}
+
+impl Piece<'_> {
+    fn sizes(&self) -> (usize, usize, usize) {
+        (self.data.0.len(), self.data.1.len(), self.data.2.len())
+    }
+
+    // The first n pairs and the rest, each encoded again on its own.
+    fn split(
+        &self,
+        n: usize,
+        compression: Option<Compression>,
+    ) -> std::io::Result<(Piece<'static>, Piece<'static>)> {
+        let raw = (
+            decode_block(&self.data.0, compression)?,
+            decode_block(&self.data.1, compression)?,
+            decode_block(&self.data.2, compression)?,
+        );
+        let cut = self.ends[n - 1];
+        let encode = |from: PairEnd, to: PairEnd| -> std::io::Result<EncodedPairs> {
+            Ok((
+                encode_block(&raw.0[from.0..to.0], compression)?,
+                encode_block(&raw.1[from.1..to.1], compression)?,
+                encode_block(&raw.2[from.2..to.2], compression)?,
+            ))
+        };
+        let head = encode((0, 0, 0), cut)?;
+        let tail = encode(cut, (raw.0.len(), raw.1.len(), raw.2.len()))?;
+        Ok((
+            Piece {
+                count: n,
+                data: (head.0.into(), head.1.into(), head.2.into()),
+                ends: self.ends[..n].to_vec().into(),
+            },
+            Piece {
+                count: self.count - n,
+                data: (tail.0.into(), tail.1.into(), tail.2.into()),
+                ends: self.ends[n..]
+                    .iter()
+                    .map(|e| (e.0 - cut.0, e.1 - cut.1, e.2 - cut.2))
+                    .collect::<Vec<PairEnd>>()
+                    .into(),
+            },
+        ))
+    }
+}
+
+impl BlockPair {
+    fn pieces(&self) -> VecDeque<Piece<'_>> {
+        let mut pieces = VecDeque::new();
+        let mut from = (0, 0, 0);
+        for segment in self.segments.iter() {
+            let to = (
+                from.0 + segment.sizes.0,
+                from.1 + segment.sizes.1,
+                from.2 + segment.sizes.2,
+            );
+            pieces.push_back(Piece {
+                count: segment.count,
+                data: (
+                    Cow::Borrowed(&self.blocks.0.data[from.0..to.0]),
+                    Cow::Borrowed(&self.blocks.1.data[from.1..to.1]),
+                    Cow::Borrowed(&self.sidecar[from.2..to.2]),
+                ),
+                ends: Cow::Borrowed(&segment.ends),
+            });
+            from = to;
+        }
+        pieces
+    }
+}
+
+pub struct OutputSummary {
+    pub key: String,
+    pub chunk: usize,
+    pub filenames: Option<(String, String)>,
+    pub count: usize,
+}
+
+pub type OutputCounts = Vec<OutputSummary>;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OutputLimits {
+    pub max_pairs: usize,
+    pub max_bytes: usize,
+}
+
+impl OutputLimits {
+    pub fn is_limited(&self) -> bool {
+        self.max_pairs > 0 || self.max_bytes > 0
+    }
+}
+
+struct OutputPair {
+    chunk: usize,
+    filenames: (String, String),
+    files: (Box<dyn Write + Send>, Box<dyn Write + Send>),
+    sidecar: Option<Box<dyn Write + Send>>,
+    count: usize,
+    bytes: (usize, usize, usize),
+}
+
+impl OutputPair {
+    // Whether count more pairs, taking these bytes in each file, stay within the limits.
+    fn has_room(&self, limits: &OutputLimits, count: usize, bytes: (usize, usize, usize)) -> bool {
+        let sidecar = if self.sidecar.is_some() { bytes.2 } else { 0 };
+        let largest = (self.bytes.0 + bytes.0)
+            .max(self.bytes.1 + bytes.1)
+            .max(self.bytes.2 + sidecar);
+        (limits.max_pairs == 0 || self.count + count <= limits.max_pairs)
+            && (limits.max_bytes == 0 || largest <= limits.max_bytes)
+    }
+
+    fn write(&mut self, count: usize, data: (&[u8], &[u8], &[u8])) -> std::io::Result<()> {
+        write_block_pair(&mut self.files, (data.0, data.1))?;
+        if let Some(sidecar) = &mut self.sidecar {
+            sidecar.write_all(data.2)?;
+            self.bytes.2 += data.2.len();
+        }
+        self.count += count;
+        self.bytes.0 += data.0.len();
+        self.bytes.1 += data.1.len();
+        Ok(())
+    }
+
+    fn summary(self, key: &str) -> OutputSummary {
+        OutputSummary {
+            key: key.to_string(),
+            chunk: self.chunk,
+            filenames: Some(self.filenames),
+            count: self.count,
+        }
+    }
+}
+
+pub fn output_filename(template: &str, placeholder: &str, key: &str) -> String {
+    if placeholder.is_empty() {
+        return template.to_string();
+    }
+    let key: String = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    template.replace(placeholder, &key)
+}
+
+fn clashing_outputs(key: &str, other: &str, filename: &str) -> std::io::Error {
+    let msg = if key == other {
+        format!(
+            "{} names two outputs, which would both be written to {}",
+            key, filename
+        )
+    } else {
+        format!(
+            "{} and {} would both be written to {}",
+            other, key, filename
+        )
+    };
+    std::io::Error::new(std::io::ErrorKind::Other, msg)
+}
+
+// Keys that differ only in characters that can't go in a filename would share a file, and
+// the second to open it would truncate the first. So would the same key given twice.
+fn check_distinct_outputs(
+    template: &str,
+    placeholder: &str,
+    keys: &[String],
+) -> std::io::Result<()> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    for key in keys.iter() {
+        let filename = output_filename(template, placeholder, key);
+        if let Some(other) = seen.insert(filename.clone(), key) {
+            return Err(clashing_outputs(key, other, &filename));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_fifo(filename: &str) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::metadata(filename) {
+        Ok(md) => md.file_type().is_fifo() || md.file_type().is_char_device(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_filename: &str) -> bool {
+    false
+}
+
+// Each block is a complete gzip member when compressing, so blocks can be concatenated in
+// any order. Empty blocks stay empty.
+pub fn encode_block(data: &[u8], compression: Option<Compression>) -> std::io::Result<Vec<u8>> {
+    match compression {
+        Some(compression) if !data.is_empty() => {
+            let mut result = Vec::with_capacity(data.len());
+            GzEncoder::new(data, compression).read_to_end(&mut result)?;
+            Ok(result)
+        }
+        _ => Ok(Vec::from(data)),
+    }
+}
+
+// "-" is stdout, and existing pipes are opened as they are rather than being recreated.
+pub fn open_output(filename: &str) -> std::io::Result<Box<dyn Write + Send>> {
+    if filename == "-" {
+        Ok(Box::new(std::io::stdout()))
+    } else if is_fifo(filename) {
+        Ok(Box::new(OpenOptions::new().write(true).open(filename)?))
+    } else {
+        Ok(Box::new(File::create(filename)?))
+    }
+}
+
+// Alternate between the two files in modest slices so that a reader consuming R1 and R2
+// from a pair of pipes in step is never starved of one while we block on the other.
+fn write_block_pair(
+    files: &mut (Box<dyn Write + Send>, Box<dyn Write + Send>),
+    blocks: (&[u8], &[u8]),
+) -> std::io::Result<()> {
+    let slice_size = 64 * 1024;
+    let mut i = 0;
+    let mut j = 0;
+    while i < blocks.0.len() || j < blocks.1.len() {
+        let i_end = blocks.0.len().min(i + slice_size);
+        files.0.write_all(&blocks.0[i..i_end])?;
+        i = i_end;
+        let j_end = blocks.1.len().min(j + slice_size);
+        files.1.write_all(&blocks.1[j..j_end])?;
+        j = j_end;
+    }
+    Ok(())
+}
+
+pub fn chunk_filename(template: &str, chunk: usize) -> String {
+    template.replace("{chunk}", &format!("{:04}", chunk))
+}
+
+fn write_blocks(
+    templates: (String, String),
+    sidecar: Option<String>,
+    placeholder: String,
+    mut keys: Vec<String>,
+    create_eagerly: bool,
+    limits: OutputLimits,
+    rx: Receiver<BlockPair>,
+) -> std::io::Result<OutputCounts> {
+    let mut outputs: HashMap<String, OutputPair> = HashMap::new();
+    let mut finished: HashMap<String, Vec<OutputSummary>> = HashMap::new();
+    // Which key each output was opened for, to catch keys that only turn up in the reads.
+    let mut owners: HashMap<String, String> = HashMap::new();
+    let open = |key: &str, chunk: usize| -> std::io::Result<OutputPair> {
+        let mut filename_0 = output_filename(&templates.0, &placeholder, key);
+        let mut filename_1 = output_filename(&templates.1, &placeholder, key);
+        if limits.is_limited() {
+            filename_0 = chunk_filename(&filename_0, chunk);
+            filename_1 = chunk_filename(&filename_1, chunk);
+        }
+        let file_0 = open_output(&filename_0)?;
+        let file_1 = open_output(&filename_1)?;
+        let sidecar = match &sidecar {
+            Some(template) => {
+                let mut filename = output_filename(template, &placeholder, key);
+                if limits.is_limited() {
+                    filename = chunk_filename(&filename, chunk);
+                }
+                Some(open_output(&filename)?)
+            }
+            None => None,
+        };
+        Ok(OutputPair {
+            chunk,
+            filenames: (filename_0, filename_1),
+            files: (file_0, file_1),
+            sidecar,
+            count: 0,
+            bytes: (0, 0, 0),
+        })
+    };
+    for key in keys.iter() {
+        owners.insert(
+            output_filename(&templates.0, &placeholder, key),
+            key.clone(),
+        );
+        if create_eagerly {
+            outputs.insert(key.clone(), open(key, 1)?);
+        }
+    }
+    for block_pair in rx {
+        if !outputs.contains_key(&block_pair.key) {
+            let filename = output_filename(&templates.0, &placeholder, &block_pair.key);
+            match owners.get(&filename) {
+                Some(other) if *other != block_pair.key => {
+                    return Err(clashing_outputs(&block_pair.key, other, &filename))
+                }
+                _ => {
+                    owners.insert(filename, block_pair.key.clone());
+                }
+            }
+            outputs.insert(block_pair.key.clone(), open(&block_pair.key, 1)?);
+            if !keys.contains(&block_pair.key) {
+                keys.push(block_pair.key.clone());
+            }
+        }
+        // Segments that would take a chunk over a limit are split between pairs, and what
+        // doesn't fit goes on to the next chunk, so R1 and R2 always roll over together.
+        let compression = block_pair.compression;
+        let mut pieces = block_pair.pieces();
+        while let Some(piece) = pieces.pop_front() {
+            let output = outputs.get_mut(&block_pair.key).unwrap();
+            if output.has_room(&limits, piece.count, piece.sizes()) {
+                output.write(piece.count, (&piece.data.0, &piece.data.1, &piece.data.2))?;
+                continue;
+            }
+            let room = if limits.max_pairs > 0 {
+                limits.max_pairs.saturating_sub(output.count)
+            } else {
+                piece.count
+            };
+            // Fill the chunk up to max_pairs, or halve what is too big, down to a single
+            // pair. A pair too big for any chunk gets one to itself.
+            let n = if room > 0 && room < piece.count {
+                room
+            } else if room > 0 && piece.count > 1 {
+                piece.count / 2
+            } else if output.count == 0 {
+                output.write(piece.count, (&piece.data.0, &piece.data.1, &piece.data.2))?;
+                continue;
+            } else {
+                0
+            };
+            if n > 0 {
+                let (head, tail) = piece.split(n, compression)?;
+                pieces.push_front(tail);
+                pieces.push_front(head);
+            } else {
+                let next = open(&block_pair.key, output.chunk + 1)?;
+                let full = std::mem::replace(output, next);
+                finished
+                    .entry(block_pair.key.clone())
+                    .or_default()
+                    .push(full.summary(&block_pair.key));
+                pieces.push_front(piece);
+            }
+        }
+    }
+    let mut res = Vec::new();
+    for key in keys {
+        let mut chunks = finished.remove(&key).unwrap_or_default();
+        match outputs.remove(&key) {
+            None => chunks.push(OutputSummary {
+                key,
+                chunk: 0,
+                filenames: None,
+                count: 0,
+            }),
+            Some(output) => chunks.push(output.summary(&key)),
+        }
+        res.append(&mut chunks);
+    }
+    Ok(res)
 }
 
 pub struct BlockPairWriter {
     compression: Option<Compression>,
-    file: Option<SyncSender<(DataBlock, DataBlock)>>,
-    joiner: Option<JoinHandle<std::io::Result<()>>>,
+    interleaved: bool,
+    limited: bool,
+    file: Option<SyncSender<BlockPair>>,
+    joiner: Option<JoinHandle<std::io::Result<OutputCounts>>>,
 }
 
 impl BlockPairWriter {
     pub fn new(
         filenames: (&str, &str),
+        sidecar: Option<&str>,
         compression: Option<Compression>,
     ) -> std::io::Result<BlockPairWriter> {
-        let inner_filename_0 = filenames.0.to_string();
-        let inner_filename_1 = filenames.1.to_string();
-        let (tx, rx) = sync_channel::<(DataBlock, DataBlock)>(1);
-        let handle = std::thread::spawn(move || {
-            let mut file_0 = File::create(&inner_filename_0)?;
-            let mut file_1 = File::create(&inner_filename_1)?;
-            for blocks in rx {
-                //println!("writing {}", blocks.0.id);
-                file_0.write_all(&blocks.0.data)?;
-                //println!("writing {}", blocks.1.id);
-                file_1.write_all(&blocks.1.data)?;
+        if (filenames.0 == "-") != (filenames.1 == "-") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "interleaved output to stdout needs both filenames to be -",
+            ));
+        }
+        BlockPairWriter::spawn(
+            filenames,
+            sidecar,
+            "",
+            vec![String::new()],
+            true,
+            OutputLimits::default(),
+            compression,
+        )
+    }
+
+    pub fn with_template(
+        templates: (&str, &str),
+        sidecar: Option<&str>,
+        placeholder: &str,
+        known_keys: Vec<String>,
+        create_eagerly: bool,
+        limits: OutputLimits,
+        compression: Option<Compression>,
+    ) -> std::io::Result<BlockPairWriter> {
+        if !templates.0.contains(placeholder) || !templates.1.contains(placeholder) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("output filename templates must contain {}", placeholder),
+            ));
+        }
+        if limits.is_limited()
+            && (!templates.0.contains("{chunk}") || !templates.1.contains("{chunk}"))
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "output filename templates must contain {chunk}",
+            ));
+        }
+        if let Some(sidecar) = sidecar {
+            if !sidecar.contains(placeholder)
+                || (limits.is_limited() && !sidecar.contains("{chunk}"))
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "the sidecar filename template must contain the same placeholders as the outputs",
+                ));
             }
-            Ok(())
+        }
+        check_distinct_outputs(templates.0, placeholder, &known_keys)?;
+        if templates.0 == templates.1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "output filename templates must differ",
+            ));
+        }
+        BlockPairWriter::spawn(
+            templates,
+            sidecar,
+            placeholder,
+            known_keys,
+            create_eagerly,
+            limits,
+            compression,
+        )
+    }
+
+    fn spawn(
+        templates: (&str, &str),
+        sidecar: Option<&str>,
+        placeholder: &str,
+        keys: Vec<String>,
+        create_eagerly: bool,
+        limits: OutputLimits,
+        compression: Option<Compression>,
+    ) -> std::io::Result<BlockPairWriter> {
+        let interleaved = templates.0 == "-" && templates.1 == "-";
+        let inner_templates = (templates.0.to_string(), templates.1.to_string());
+        let inner_sidecar = sidecar.map(|sidecar| sidecar.to_string());
+        let inner_placeholder = placeholder.to_string();
+        let (tx, rx) = sync_channel::<BlockPair>(1);
+        let handle = std::thread::spawn(move || {
+            write_blocks(
+                inner_templates,
+                inner_sidecar,
+                inner_placeholder,
+                keys,
+                create_eagerly,
+                limits,
+                rx,
+            )
         });
         Ok(BlockPairWriter {
             compression,
+            interleaved,
+            limited: limits.is_limited(),
             file: Some(tx),
             joiner: Some(handle),
         })
     }
 
+    pub fn is_interleaved(&self) -> bool {
+        self.interleaved
+    }
+
     pub fn writers(&self, id: &str) -> std::io::Result<LocalBlockPairWriter> {
         Ok(LocalBlockPairWriter {
             compression: self.compression,
+            interleaved: self.interleaved,
+            limited: self.limited,
             id: id.to_string(),
             block_num: 0,
             writers: self.file.clone().unwrap(),
         })
     }
 
-    pub fn finish(&mut self) -> std::io::Result<()> {
+    pub fn finish(&mut self) -> std::io::Result<OutputCounts> {
         self.file.take();
         match self.joiner.take() {
             None => Err(std::io::Error::new(
@@ -62,52 +608,201 @@ impl BlockPairWriter {
             )),
             Some(joiner) => joiner
                 .join()
-                .map(|_| ())
-                .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "join failed")),
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "join failed"))?,
         }
     }
 }
 
 pub struct LocalBlockPairWriter {
     compression: Option<Compression>,
+    interleaved: bool,
+    limited: bool,
     id: String,
     block_num: usize,
-    writers: SyncSender<(DataBlock, DataBlock)>,
+    writers: SyncSender<BlockPair>,
 }
 
 impl LocalBlockPairWriter {
-    pub fn write(&mut self, blocks: (&[u8], &[u8])) -> std::io::Result<()> {
+    pub fn is_interleaved(&self) -> bool {
+        self.interleaved
+    }
+
+    pub fn write(
+        &mut self,
+        key: &str,
+        blocks: (&[u8], &[u8]),
+        sidecar: &[u8],
+        ends: &[PairEnd],
+    ) -> std::io::Result<()> {
         self.block_num += 1;
-        let block_id_1 = format!("1\t{}:{}", self.id, self.block_num);
-        let block_id_2 = format!("2\t{}:{}", self.id, self.block_num);
-        let data_0 = if let Some(compression) = &self.compression {
-            let mut result = Vec::with_capacity(blocks.0.len());
-            let mut gz = GzEncoder::new(blocks.0, compression.clone());
-            gz.read_to_end(&mut result).unwrap();
-            result
-        } else {
-            Vec::from(blocks.0)
-        };
-        let data_1 = if let Some(compression) = &self.compression {
-            let mut result = Vec::with_capacity(blocks.1.len());
-            let mut gz = GzEncoder::new(blocks.1, compression.clone());
-            gz.read_to_end(&mut result).unwrap();
-            result
+        let block_id_1 = format!("1\t{}:{}:{}", self.id, key, self.block_num);
+        let block_id_2 = format!("2\t{}:{}:{}", self.id, key, self.block_num);
+        let segment_size = if self.limited {
+            SEGMENT_SIZE
         } else {
-            Vec::from(blocks.0)
+            usize::MAX
         };
+        let (data, segments) = encode_segments(
+            (blocks.0, blocks.1, sidecar),
+            ends,
+            segment_size,
+            self.compression,
+        )?;
         self.writers
-            .send((
-                DataBlock {
-                    id: block_id_1,
-                    data: data_0,
-                },
-                DataBlock {
-                    id: block_id_2,
-                    data: data_1,
-                },
-            ))
-            .unwrap();
-        Ok(())
+            .send(BlockPair {
+                key: key.to_string(),
+                blocks: (
+                    DataBlock {
+                        id: block_id_1,
+                        data: data.0,
+                    },
+                    DataBlock {
+                        id: block_id_2,
+                        data: data.1,
+                    },
+                ),
+                sidecar: data.2,
+                segments,
+                compression: self.compression,
+            })
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "writer thread exited"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    // n pairs of four line records, with where each pair ends.
+    fn records(first: usize, n: usize) -> (Vec<u8>, Vec<PairEnd>) {
+        let mut data = Vec::new();
+        let mut ends = Vec::new();
+        for i in first..first + n {
+            data.extend(format!("@r{}\nACGT\n+\nIIII\n", i).bytes());
+            ends.push((data.len(), data.len(), 0));
+        }
+        (data, ends)
+    }
+
+    fn write_chunks(
+        name: &str,
+        limits: OutputLimits,
+        compression: Option<Compression>,
+        blocks: &[usize],
+    ) -> Vec<(usize, Vec<u8>)> {
+        let dir = std::env::temp_dir().join(format!("mazab-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let template_0 = dir.join("{chunk}_R1.fq").to_string_lossy().to_string();
+        let template_1 = dir.join("{chunk}_R2.fq").to_string_lossy().to_string();
+        let mut writer = BlockPairWriter::with_template(
+            (&template_0, &template_1),
+            None,
+            "",
+            vec![String::new()],
+            true,
+            limits,
+            compression,
+        )
+        .unwrap();
+        let mut local = writer.writers("0").unwrap();
+        let mut first = 0;
+        for n in blocks.iter() {
+            let (data, ends) = records(first, *n);
+            local.write("", (&data, &data), &[], &ends).unwrap();
+            first += n;
+        }
+        drop(local);
+        let chunks = writer
+            .finish()
+            .unwrap()
+            .into_iter()
+            .map(|summary| {
+                let (r1, r2) = summary.filenames.unwrap();
+                let data = std::fs::read(r1).unwrap();
+                assert_eq!(data, std::fs::read(r2).unwrap());
+                (summary.count, data)
+            })
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        chunks
+    }
+
+    #[test]
+    fn chunks_hold_at_most_max_pairs() {
+        let limits = OutputLimits {
+            max_pairs: 4,
+            max_bytes: 0,
+        };
+        let chunks = write_chunks("pairs", limits, None, &[3, 7, 1]);
+        let counts: Vec<usize> = chunks.iter().map(|(count, _)| *count).collect();
+        assert_eq!(counts, vec![4, 4, 3]);
+        assert_eq!(chunks[1].1, records(4, 4).0);
+    }
+
+    #[test]
+    fn chunks_hold_at_most_max_bytes() {
+        // Each record is 17 bytes, or 18 from r10 on.
+        let limits = OutputLimits {
+            max_pairs: 0,
+            max_bytes: 40,
+        };
+        let chunks = write_chunks("bytes", limits, None, &[5, 8]);
+        let counts: Vec<usize> = chunks.iter().map(|(count, _)| *count).collect();
+        assert_eq!(counts, vec![2, 2, 2, 2, 2, 2, 1]);
+        assert!(chunks.iter().all(|(_, data)| data.len() <= 40));
+        assert_eq!(chunks[2].1, records(4, 2).0);
+
+        // A pair bigger than the limit still gets written, in a chunk of its own.
+        let limits = OutputLimits {
+            max_pairs: 0,
+            max_bytes: 10,
+        };
+        let counts: Vec<usize> = write_chunks("oversize", limits, None, &[3])
+            .iter()
+            .map(|(count, _)| *count)
+            .collect();
+        assert_eq!(counts, vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn compressed_chunks_split_within_segments() {
+        // Enough pairs for a block to be encoded as several segments.
+        let limits = OutputLimits {
+            max_pairs: 7000,
+            max_bytes: 20 * 1024,
+        };
+        let compression = Some(Compression::default());
+        let chunks = write_chunks("compressed", limits, compression, &[20000, 3]);
+        let mut data = Vec::new();
+        for (count, chunk) in chunks.iter() {
+            assert!(chunk.len() <= limits.max_bytes);
+            assert!(*count <= limits.max_pairs);
+            let decoded = decode_block(chunk, compression).unwrap();
+            assert_eq!(decoded.iter().filter(|c| **c == b'\n').count(), 4 * count);
+            data.extend(decoded);
+        }
+        assert!(chunks.len() > 1);
+        assert_eq!(data, records(0, 20003).0);
+    }
+
+    #[test]
+    fn sanitised_keys_that_clash() {
+        let template = "{rg}_R1.fq.gz";
+        assert!(check_distinct_outputs(template, "{rg}", &keys(&["a:b", "a_c"])).is_ok());
+        let err = check_distinct_outputs(template, "{rg}", &keys(&["a:b", "a_b"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "a:b and a_b would both be written to a_b_R1.fq.gz"
+        );
+        let err = check_distinct_outputs(template, "{rg}", &keys(&["rg1", "unknown", "unknown"]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown names two outputs, which would both be written to unknown_R1.fq.gz"
+        );
     }
 }
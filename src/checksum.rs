@@ -1,10 +1,19 @@
 use std::collections::BinaryHeap;
 use std::io::{stdout, BufReader, Write};
+use std::sync::{
+    mpsc::{channel, sync_channel, Receiver, SyncSender},
+    Arc, Mutex,
+};
+use std::thread::JoinHandle;
 
-use noodles::fastq::{Reader, Writer};
+use noodles::fastq::{self, Reader, Writer};
+use noodles::sam::alignment::Record;
 use sha2::{digest::FixedOutput, Digest, Sha256};
+use threadpool::ThreadPool;
 
 use crate::files::open_reader;
+use crate::formatter::{orient_pair, FormatOptions, PairFormatter};
+use crate::sketch::Sketch;
 
 fn hexy(xs: &[u8]) -> String {
     let s: [char; 16] = [
@@ -18,12 +27,76 @@ fn hexy(xs: &[u8]) -> String {
     res
 }
 
-#[derive(Debug, Eq, PartialEq)]
+pub const DEFAULT_SKETCH_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DigestMode {
+    Full,
+    SeqQual,
+    SeqOnly,
+    NormalisedName,
+}
+
+impl DigestMode {
+    pub fn parse(txt: &str) -> std::io::Result<DigestMode> {
+        match txt {
+            "full" => Ok(DigestMode::Full),
+            "seq-qual" => Ok(DigestMode::SeqQual),
+            "seq" => Ok(DigestMode::SeqOnly),
+            "norm-name" => Ok(DigestMode::NormalisedName),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("invalid digest mode '{}'", txt),
+            )),
+        }
+    }
+
+    // The text hashed for one mate in the modes other than Full, which hashes the FASTQ record as written.
+    fn write_mate(&self, out: &mut Vec<u8>, name: &[u8], seq: &[u8], qual: &[u8]) {
+        if *self == DigestMode::NormalisedName {
+            out.push(b'@');
+            out.extend_from_slice(normalise_name(name));
+            out.push(b'\n');
+        }
+        out.extend_from_slice(seq);
+        out.push(b'\n');
+        if *self != DigestMode::SeqOnly {
+            if *self == DigestMode::NormalisedName {
+                out.extend_from_slice(b"+\n");
+            }
+            out.extend_from_slice(qual);
+            out.push(b'\n');
+        }
+    }
+}
+
+// Drop any comment and a trailing /1 or /2.
+pub fn normalise_name(name: &[u8]) -> &[u8] {
+    let end = name
+        .iter()
+        .position(|c| c.is_ascii_whitespace())
+        .unwrap_or(name.len());
+    let name = &name[..end];
+    if name.ends_with(b"/1") || name.ends_with(b"/2") {
+        &name[..name.len() - 2]
+    } else {
+        name
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 struct HashAndText {
     hash: String,
     text: Vec<u8>,
 }
 
+impl HashAndText {
+    // The leading 64 bits of the hash, which is what a Sketch keeps.
+    fn sketch_hash(&self) -> u64 {
+        u64::from_str_radix(&self.hash[..16], 16).unwrap()
+    }
+}
+
 impl Ord for HashAndText {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.hash.cmp(&other.hash)
@@ -36,19 +109,455 @@ impl PartialOrd for HashAndText {
     }
 }
 
-pub fn compute_checksum(filename_1: &str, filename_2: &str) -> std::io::Result<Vec<u8>> {
-    let mut reader1 = open_reader(filename_1)
-        .map(BufReader::new)
-        .map(Reader::new)?;
-    let mut reader2 = open_reader(filename_2)
-        .map(BufReader::new)
-        .map(Reader::new)?;
+pub struct PairDigest {
+    pub count: usize,
+    pub digest: Vec<u8>,
+    sketch_size: usize,
+    sketch: BinaryHeap<HashAndText>,
+}
+
+impl PairDigest {
+    pub fn new() -> PairDigest {
+        PairDigest::with_sketch_size(DEFAULT_SKETCH_SIZE)
+    }
+
+    pub fn with_sketch_size(sketch_size: usize) -> PairDigest {
+        PairDigest {
+            count: 0,
+            digest: Vec::new(),
+            sketch_size,
+            sketch: BinaryHeap::new(),
+        }
+    }
+
+    pub fn sketch_size(&self) -> usize {
+        self.sketch_size
+    }
+
+    pub fn add(&mut self, text: &[u8]) {
+        let mut hasher: Sha256 = Sha256::new();
+        hasher.update(text);
+        let xs: Vec<u8> = Vec::from_iter(hasher.finalize_fixed());
+        self.fold(&xs);
+        self.count += 1;
+        self.add_to_sketch(HashAndText {
+            hash: hexy(&xs),
+            text: Vec::from(text),
+        });
+    }
+
+    pub fn merge(&mut self, other: PairDigest) {
+        self.fold(&other.digest);
+        self.count += other.count;
+        for hat in other.sketch {
+            self.add_to_sketch(hat);
+        }
+    }
+
+    pub fn hex(&self) -> String {
+        hexy(&self.digest)
+    }
+
+    pub fn sketch_hashes(&self) -> Vec<String> {
+        self.sketch
+            .clone()
+            .into_sorted_vec()
+            .into_iter()
+            .map(|hat| hat.hash)
+            .collect()
+    }
+
+    pub fn matches(&self, other: &PairDigest) -> bool {
+        self.count == other.count && self.digest == other.digest
+    }
+
+    pub fn to_sketch(&self) -> Sketch {
+        let hashes = self.sketch.iter().map(|hat| hat.sketch_hash()).collect();
+        Sketch::new(self.sketch_size, self.count, self.digest.clone(), hashes)
+    }
+
+    pub fn jaccard(&self, other: &PairDigest) -> f64 {
+        self.to_sketch().jaccard(&other.to_sketch())
+    }
+
+    // Names of sketched pairs that are missing from the other side, within the hash range both sketches cover.
+    pub fn sketch_only_in(&self, other: &PairDigest) -> Vec<String> {
+        let theirs = other.to_sketch();
+        let limit = self.to_sketch().common_limit(&theirs);
+        let mut res = Vec::new();
+        for hat in self.sketch.clone().into_sorted_vec() {
+            let hash = hat.sketch_hash();
+            if hash > limit {
+                break;
+            }
+            if theirs.hashes.binary_search(&hash).is_err() {
+                res.push(read_name(&hat.text));
+            }
+        }
+        res
+    }
+
+    pub fn write_report(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "number of read pairs: {}", self.count)?;
+        writeln!(out, "{}", self.hex())?;
+        writeln!(out, "sketch:")?;
+        for hat in self.sketch.clone().into_sorted_vec().iter().rev() {
+            out.write_all(&hat.text)?;
+        }
+        Ok(())
+    }
+
+    fn fold(&mut self, xs: &[u8]) {
+        if self.digest.len() < xs.len() {
+            self.digest.resize(xs.len(), 0);
+        }
+        for (d, x) in self.digest.iter_mut().zip(xs) {
+            *d ^= *x;
+        }
+    }
+
+    fn add_to_sketch(&mut self, hat: HashAndText) {
+        self.sketch.push(hat);
+        while self.sketch.len() > self.sketch_size {
+            let _discard = self.sketch.pop();
+        }
+    }
+}
+
+impl Default for PairDigest {
+    fn default() -> Self {
+        PairDigest::new()
+    }
+}
 
-    let mut sketch: BinaryHeap<HashAndText> = BinaryHeap::new();
+fn read_name(text: &[u8]) -> String {
+    let line = text.split(|c| *c == b'\n').next().unwrap_or_default();
+    String::from_utf8_lossy(line.strip_prefix(b"@").unwrap_or(line)).to_string()
+}
 
-    let mut recs1 = reader1.records();
-    let mut recs2 = reader2.records();
-    let mut m = Vec::new();
+// Digests pairs on their way out of the pairer, exactly as they would be written to FASTQ.
+pub struct DigestFormatter {
+    mode: DigestMode,
+    options: FormatOptions,
+    digest: PairDigest,
+    total: Arc<Mutex<PairDigest>>,
+}
+
+impl DigestFormatter {
+    pub fn new(
+        total: Arc<Mutex<PairDigest>>,
+        mode: DigestMode,
+        options: FormatOptions,
+    ) -> DigestFormatter {
+        let sketch_size = total.lock().unwrap().sketch_size();
+        DigestFormatter {
+            mode,
+            options,
+            digest: PairDigest::with_sketch_size(sketch_size),
+            total,
+        }
+    }
+}
+
+impl PairFormatter for DigestFormatter {
+    fn write(&mut self, pair: (Record, Record)) -> std::io::Result<()> {
+        let (r1, r2) = orient_pair(pair);
+        let read_id = self.options.read_id(&r1);
+        let mut text = Vec::new();
+        if self.mode == DigestMode::Full {
+            self.options.write_fastq_record(&mut text, &read_id, &r1)?;
+            self.options.write_fastq_record(&mut text, &read_id, &r2)?;
+        } else {
+            for rec in [&r1, &r2] {
+                let seq = self.options.sequence(rec);
+                let qual = self.options.quality(rec)?;
+                self.mode.write_mate(
+                    &mut text,
+                    read_id.as_bytes(),
+                    seq.as_bytes(),
+                    qual.as_bytes(),
+                );
+            }
+        }
+        self.digest.add(&text);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let sketch_size = self.digest.sketch_size();
+        let digest = std::mem::replace(&mut self.digest, PairDigest::with_sketch_size(sketch_size));
+        self.total.lock().unwrap().merge(digest);
+        Ok(())
+    }
+}
+
+fn json_string(txt: &str) -> String {
+    let mut res = String::from("\"");
+    for c in txt.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+pub struct NameMismatch {
+    pub record: usize,
+    pub names: (String, String),
+}
+
+pub struct Truncation {
+    pub short_file: String,
+    pub long_file: String,
+    pub record: usize,
+}
+
+pub struct Checksum {
+    pub digest: PairDigest,
+    pub mismatch_count: usize,
+    pub mismatches: Vec<NameMismatch>,
+    pub truncated: Option<Truncation>,
+}
+
+impl Checksum {
+    pub fn is_clean(&self) -> bool {
+        self.mismatch_count == 0 && self.truncated.is_none()
+    }
+
+    pub fn write_report(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        self.write_problems(out)?;
+        self.digest.write_report(out)
+    }
+
+    pub fn write_problems(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        if let Some(truncation) = &self.truncated {
+            writeln!(
+                out,
+                "{} ran out of records before {} (at record {})",
+                truncation.short_file, truncation.long_file, truncation.record
+            )?;
+        }
+        for mismatch in self.mismatches.iter() {
+            writeln!(
+                out,
+                "read {}: mismatched record IDs: {} {}",
+                mismatch.record, mismatch.names.0, mismatch.names.1
+            )?;
+        }
+        if self.mismatch_count > 0 {
+            writeln!(out, "mismatched record IDs: {}", self.mismatch_count)?;
+        }
+        Ok(())
+    }
+
+    // Write the results as text or JSON, optionally save the sketch, and fail on mismatched or truncated input.
+    pub fn report(&self, json: bool, sketch_file: Option<&str>) -> std::io::Result<()> {
+        if json {
+            self.write_json(&mut stdout())?;
+        } else {
+            self.write_report(&mut stdout())?;
+        }
+        if let Some(sketch_file) = sketch_file {
+            self.digest.to_sketch().save(sketch_file)?;
+        }
+        if let Some(truncation) = &self.truncated {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("{} ran out of records first", truncation.short_file),
+            ));
+        }
+        if self.mismatch_count > 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("{} pairs had mismatched record IDs", self.mismatch_count),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"pairs\": {},", self.digest.count)?;
+        writeln!(out, "  \"digest\": {},", json_string(&self.digest.hex()))?;
+        writeln!(out, "  \"mismatch_count\": {},", self.mismatch_count)?;
+        let mismatches: Vec<String> = self
+            .mismatches
+            .iter()
+            .map(|m| {
+                format!(
+                    "{{\"record\": {}, \"names\": [{}, {}]}}",
+                    m.record,
+                    json_string(&m.names.0),
+                    json_string(&m.names.1)
+                )
+            })
+            .collect();
+        writeln!(out, "  \"mismatches\": [{}],", mismatches.join(", "))?;
+        match &self.truncated {
+            None => writeln!(out, "  \"truncated\": null,")?,
+            Some(truncation) => writeln!(
+                out,
+                "  \"truncated\": {{\"short_file\": {}, \"long_file\": {}, \"record\": {}}},",
+                json_string(&truncation.short_file),
+                json_string(&truncation.long_file),
+                truncation.record
+            )?,
+        }
+        let hashes: Vec<String> = self
+            .digest
+            .sketch_hashes()
+            .iter()
+            .map(|h| json_string(h))
+            .collect();
+        writeln!(out, "  \"sketch\": [{}]", hashes.join(", "))?;
+        writeln!(out, "}}")
+    }
+}
+
+impl From<PairDigest> for Checksum {
+    fn from(digest: PairDigest) -> Self {
+        Checksum {
+            digest,
+            mismatch_count: 0,
+            mismatches: Vec::new(),
+            truncated: None,
+        }
+    }
+}
+
+const BATCH_SIZE: usize = 4096;
+
+type RecordBatch = Vec<std::io::Result<fastq::Record>>;
+type PairBatch = Vec<(fastq::Record, fastq::Record)>;
+
+// Parse (and decompress) a FASTQ file on its own thread, handing records over in batches.
+fn spawn_reader(filename: &str) -> (Receiver<RecordBatch>, JoinHandle<()>) {
+    let filename = filename.to_string();
+    let (tx, rx) = sync_channel::<RecordBatch>(4);
+    let handle = std::thread::spawn(move || {
+        let mut reader = match open_reader(&filename) {
+            Ok(inner) => Reader::new(BufReader::new(inner)),
+            Err(err) => {
+                let _ = tx.send(vec![Err(err)]);
+                return;
+            }
+        };
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        for res in reader.records() {
+            let failed = res.is_err();
+            batch.push(res);
+            if failed || batch.len() == BATCH_SIZE {
+                let sent = tx.send(std::mem::take(&mut batch));
+                if sent.is_err() || failed {
+                    return;
+                }
+            }
+        }
+        if !batch.is_empty() {
+            let _ = tx.send(batch);
+        }
+    });
+    (rx, handle)
+}
+
+fn hash_pairs(batch: PairBatch, mode: DigestMode, digest: &mut PairDigest) -> std::io::Result<()> {
+    for (lhs, rhs) in batch {
+        if mode == DigestMode::Full {
+            let mut w = Writer::new(Vec::new());
+            w.write_record(&lhs)?;
+            w.write_record(&rhs)?;
+            digest.add(w.get_ref());
+        } else {
+            let mut text = Vec::new();
+            for rec in [&lhs, &rhs] {
+                mode.write_mate(&mut text, rec.name(), rec.sequence(), rec.quality_scores());
+            }
+            digest.add(&text);
+        }
+    }
+    Ok(())
+}
+
+// Pairs whose names disagree are counted and left out of the digest; reading stops when either file runs out.
+// Each hashing thread keeps its own digest and they are merged at the end, which gives the same result
+// as hashing serially.
+pub fn fastq_digest(
+    filename_1: &str,
+    filename_2: &str,
+    mode: DigestMode,
+    sketch_size: usize,
+    num_threads: usize,
+) -> std::io::Result<Checksum> {
+    let (rx1, reader1) = spawn_reader(filename_1);
+    let (rx2, reader2) = spawn_reader(filename_2);
+
+    let pool = ThreadPool::new(num_threads.max(1));
+    let (batch_tx, batch_rx) = sync_channel::<PairBatch>(2 * pool.max_count());
+    let batch_rx = Arc::new(Mutex::new(batch_rx));
+    let (digest_tx, digest_rx) = channel::<std::io::Result<PairDigest>>();
+    for _ in 0..pool.max_count() {
+        let batch_rx = batch_rx.clone();
+        let digest_tx = digest_tx.clone();
+        pool.execute(move || {
+            let mut digest = PairDigest::with_sketch_size(sketch_size);
+            let mut res = Ok(());
+            loop {
+                let batch = match batch_rx.lock().unwrap().recv() {
+                    Ok(batch) => batch,
+                    Err(_) => break,
+                };
+                res = res.and_then(|_| hash_pairs(batch, mode, &mut digest));
+            }
+            digest_tx.send(res.map(|_| digest)).expect("send failed");
+        });
+    }
+    drop(digest_tx);
+
+    let mut res = Checksum::from(PairDigest::with_sketch_size(sketch_size));
+    let failure = read_pairs(
+        (filename_1, filename_2),
+        rx1.iter().flatten(),
+        rx2.iter().flatten(),
+        mode,
+        &batch_tx,
+        &mut res,
+    );
+    drop(batch_tx);
+
+    // Stopping early can leave the readers blocked on a full channel, so let them go first.
+    drop(rx1);
+    drop(rx2);
+    reader1.join().expect("reader thread panicked");
+    reader2.join().expect("reader thread panicked");
+
+    for digest in digest_rx {
+        res.digest.merge(digest?);
+    }
+    pool.join();
+    failure?;
+    Ok(res)
+}
+
+fn read_pairs<Src1, Src2>(
+    filenames: (&str, &str),
+    mut recs1: Src1,
+    mut recs2: Src2,
+    mode: DigestMode,
+    batch_tx: &SyncSender<PairBatch>,
+    res: &mut Checksum,
+) -> std::io::Result<()>
+where
+    Src1: Iterator<Item = std::io::Result<fastq::Record>>,
+    Src2: Iterator<Item = std::io::Result<fastq::Record>>,
+{
+    let (filename_1, filename_2) = filenames;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
     let mut rn = 0;
     loop {
         match (recs1.next(), recs2.next()) {
@@ -56,63 +565,58 @@ pub fn compute_checksum(filename_1: &str, filename_2: &str) -> std::io::Result<V
                 break;
             }
             (Some(lhs_res), None) => {
-                println!(
-                    "{} ran out of records before {} (hanging record is '{:?}'",
-                    filename_2, filename_1, lhs_res
-                );
+                lhs_res?;
+                res.truncated = Some(Truncation {
+                    short_file: filename_2.to_string(),
+                    long_file: filename_1.to_string(),
+                    record: rn + 1,
+                });
                 break;
             }
             (None, Some(rhs_res)) => {
-                println!(
-                    "{} ran out of records before {} (hanging record is '{:?}'",
-                    filename_1, filename_2, rhs_res
-                );
+                rhs_res?;
+                res.truncated = Some(Truncation {
+                    short_file: filename_1.to_string(),
+                    long_file: filename_2.to_string(),
+                    record: rn + 1,
+                });
                 break;
             }
             (Some(lhs_res), Some(rhs_res)) => {
                 rn += 1;
                 let lhs = lhs_res?;
                 let rhs = rhs_res?;
-                if lhs.name() != rhs.name() {
-                    println!(
-                        "read {}: mismatched record IDs: {} {}",
-                        rn,
-                        std::str::from_utf8(lhs.name()).unwrap(),
-                        std::str::from_utf8(rhs.name()).unwrap()
-                    );
-                    break;
-                }
-                let mut w = Writer::new(Vec::new());
-                w.write_record(&lhs)?;
-                w.write_record(&rhs)?;
-                let s = w.get_ref();
-                let mut hasher: Sha256 = Sha256::new();
-                hasher.update(s);
-                let xs: Vec<u8> = Vec::from_iter(hasher.finalize_fixed());
-                let h = hexy(&xs);
-                let hat = HashAndText {
-                    hash: h,
-                    text: s.clone(),
+                let names_match = if mode == DigestMode::Full {
+                    lhs.name() == rhs.name()
+                } else {
+                    normalise_name(lhs.name()) == normalise_name(rhs.name())
                 };
-                sketch.push(hat);
-                while sketch.len() > 1000 {
-                    let _discard = sketch.pop();
-                }
-                //println!("{}", hexy(&xs));
-                for i in 0..xs.len() {
-                    while m.len() <= i {
-                        m.push(0);
+                if !names_match {
+                    res.mismatch_count += 1;
+                    if res.mismatches.len() < 100 {
+                        res.mismatches.push(NameMismatch {
+                            record: rn,
+                            names: (
+                                String::from_utf8_lossy(lhs.name()).to_string(),
+                                String::from_utf8_lossy(rhs.name()).to_string(),
+                            ),
+                        });
                     }
-                    m[i] ^= xs[i];
+                    continue;
+                }
+                batch.push((lhs, rhs));
+                if batch.len() == BATCH_SIZE {
+                    batch_tx.send(std::mem::take(&mut batch)).map_err(|_| {
+                        std::io::Error::new(std::io::ErrorKind::Other, "hashing thread exited")
+                    })?;
                 }
             }
         }
     }
-    println!("number of read pairs: {}", rn);
-    println!("{}", hexy(&m));
-    println!("sketch:");
-    while let Some(hat) = sketch.pop() {
-        stdout().write_all(&hat.text)?;
+    if !batch.is_empty() {
+        batch_tx
+            .send(batch)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "hashing thread exited"))?;
     }
-    Ok(m)
+    Ok(())
 }
@@ -0,0 +1,122 @@
+use std::collections::{HashMap, HashSet};
+
+use noodles::sam::{
+    alignment::Record,
+    record::{cigar::op::Kind, Flags},
+};
+
+use crate::formatter::read_group;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DuplicatePolicy {
+    #[default]
+    Keep,
+    Drop,
+    Annotate,
+}
+
+impl DuplicatePolicy {
+    pub fn parse(txt: &str) -> std::io::Result<DuplicatePolicy> {
+        match txt {
+            "keep" => Ok(DuplicatePolicy::Keep),
+            "drop" => Ok(DuplicatePolicy::Drop),
+            "annotate" => Ok(DuplicatePolicy::Annotate),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("unknown duplicate policy: {}", txt),
+            )),
+        }
+    }
+}
+
+pub fn is_duplicate(pair: &(Record, Record)) -> bool {
+    pair.0.flags().is_duplicate() || pair.1.flags().is_duplicate()
+}
+
+// Duplicates are marked as a pair, so either mate being marked marks both.
+pub fn mark_duplicate(pair: &mut (Record, Record)) {
+    pair.0.flags_mut().insert(Flags::DUPLICATE);
+    pair.1.flags_mut().insert(Flags::DUPLICATE);
+}
+
+// Where sequencing of a mapped read started: its reference, unclipped 5' position and strand.
+type FivePrimeEnd = (usize, i64, bool);
+
+fn five_prime_end(rec: &Record) -> Option<FivePrimeEnd> {
+    if rec.flags().is_unmapped() {
+        return None;
+    }
+    let id = rec.reference_sequence_id()?;
+    let is_clip = |kind: Kind| matches!(kind, Kind::SoftClip | Kind::HardClip);
+    let ops = rec.cigar().as_ref();
+    let reverse = rec.flags().is_reverse_complemented();
+    let position = if reverse {
+        let clipped: usize = ops
+            .iter()
+            .rev()
+            .take_while(|op| is_clip(op.kind()))
+            .map(|op| op.len())
+            .sum();
+        (rec.alignment_end()?.get() + clipped) as i64
+    } else {
+        let clipped: usize = ops
+            .iter()
+            .take_while(|op| is_clip(op.kind()))
+            .map(|op| op.len())
+            .sum();
+        rec.alignment_start()?.get() as i64 - clipped as i64
+    };
+    Some((id, position, reverse))
+}
+
+// A five prime end packed into a u64: a set top bit, then the reference id, the position
+// (offset so that clipping before the start of a reference stays positive) and the strand.
+// Nothing else packs to 0, which stands for an unmapped mate.
+fn pack(end: Option<FivePrimeEnd>) -> u64 {
+    match end {
+        Some((id, position, reverse)) => {
+            let position = (position + (1 << 31)) as u64 & ((1 << 33) - 1);
+            1 << 63 | (id as u64) << 34 | position << 1 | reverse as u64
+        }
+        None => 0,
+    }
+}
+
+// Finds pairs whose mates start where an earlier pair's did, on the same strands and in the
+// same read group. The first such pair is the one kept. Pairs with neither mate mapped are
+// never duplicates. Read groups are numbered as they are met, so that a pair costs a fixed
+// 24 bytes to remember.
+#[derive(Default)]
+pub struct DuplicateDetector {
+    read_groups: HashMap<String, u32>,
+    seen: HashSet<(u32, u64, u64)>,
+}
+
+impl DuplicateDetector {
+    pub fn new() -> DuplicateDetector {
+        DuplicateDetector::default()
+    }
+
+    fn read_group_id(&mut self, rg: Option<&str>) -> u32 {
+        let Some(rg) = rg else {
+            return 0;
+        };
+        if let Some(id) = self.read_groups.get(rg) {
+            return *id;
+        }
+        let id = self.read_groups.len() as u32 + 1;
+        self.read_groups.insert(rg.to_string(), id);
+        id
+    }
+
+    pub fn check(&mut self, pair: &(Record, Record)) -> bool {
+        let a = pack(five_prime_end(&pair.0));
+        let b = pack(five_prime_end(&pair.1));
+        if a == 0 && b == 0 {
+            return false;
+        }
+        let (a, b) = if a <= b { (a, b) } else { (b, a) };
+        let rg = self.read_group_id(read_group(&pair.0));
+        !self.seen.insert((rg, a, b))
+    }
+}
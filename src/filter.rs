@@ -0,0 +1,245 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Write,
+    path::Path,
+};
+
+use noodles::sam::{self, alignment::Record};
+
+use crate::trim::TrimCounts;
+
+// The names `samtools view` accepts for the flag bits.
+const FLAG_NAMES: [(&str, u16); 12] = [
+    ("PAIRED", 0x1),
+    ("PROPER_PAIR", 0x2),
+    ("UNMAP", 0x4),
+    ("MUNMAP", 0x8),
+    ("REVERSE", 0x10),
+    ("MREVERSE", 0x20),
+    ("READ1", 0x40),
+    ("READ2", 0x80),
+    ("SECONDARY", 0x100),
+    ("QCFAIL", 0x200),
+    ("DUP", 0x400),
+    ("SUPPLEMENTARY", 0x800),
+];
+
+// A flag mask as samtools takes it: decimal, hex with 0x, or a comma separated list of names.
+pub fn parse_flags(txt: &str) -> std::io::Result<u16> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("invalid flag mask: {}", txt),
+        )
+    };
+    if let Some(hex) = txt.strip_prefix("0x").or_else(|| txt.strip_prefix("0X")) {
+        return u16::from_str_radix(hex, 16).map_err(|_| invalid());
+    }
+    if txt.chars().all(|c| c.is_ascii_digit()) {
+        return txt.parse::<u16>().map_err(|_| invalid());
+    }
+    let mut bits = 0;
+    for name in txt.split(',') {
+        let name = name.trim().to_ascii_uppercase();
+        match FLAG_NAMES.iter().find(|(n, _)| *n == name) {
+            Some((_, bit)) => bits |= bit,
+            None => return Err(invalid()),
+        }
+    }
+    Ok(bits)
+}
+
+// Which of the two mates a predicate has to hold for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mates {
+    Both,
+    Either,
+    One,
+    Neither,
+}
+
+impl Mates {
+    pub fn parse(txt: &str) -> std::io::Result<Mates> {
+        match txt {
+            "both" => Ok(Mates::Both),
+            "either" => Ok(Mates::Either),
+            "one" => Ok(Mates::One),
+            "neither" => Ok(Mates::Neither),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("expected both, either, one or neither, not {}", txt),
+            )),
+        }
+    }
+
+    pub fn holds(&self, a: bool, b: bool) -> bool {
+        match self {
+            Mates::Both => a && b,
+            Mates::Either => a || b,
+            Mates::One => a != b,
+            Mates::Neither => !a && !b,
+        }
+    }
+}
+
+// Contig names, given either as a comma separated list or as a file with one name per line.
+pub fn parse_names(txt: &str) -> std::io::Result<Vec<String>> {
+    let names = if Path::new(txt).is_file() {
+        std::fs::read_to_string(txt)?
+            .lines()
+            .map(|line| line.trim().to_string())
+            .collect::<Vec<String>>()
+    } else {
+        txt.split(',').map(|name| name.trim().to_string()).collect()
+    };
+    Ok(names.into_iter().filter(|name| !name.is_empty()).collect())
+}
+
+pub fn contig_ids(header: &sam::Header, names: &[String]) -> std::io::Result<HashSet<usize>> {
+    let mut ids = HashSet::new();
+    for name in names.iter() {
+        match header.reference_sequences().get_index_of(name.as_str()) {
+            Some(id) => ids.insert(id),
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("no reference sequence named {} in the BAM header", name),
+                ))
+            }
+        };
+    }
+    Ok(ids)
+}
+
+fn mapped_contig(rec: &Record) -> Option<usize> {
+    if rec.flags().is_unmapped() {
+        None
+    } else {
+        rec.reference_sequence_id()
+    }
+}
+
+// Pairs turned away by the filters, counted once under each reason that applies, the
+// duplicate pairs that got past them, what trimming took off the rest, and how many of
+// their base qualities binning changed.
+#[derive(Clone, Debug, Default)]
+pub struct FilterCounts {
+    pub pairs: usize,
+    pub reasons: BTreeMap<String, usize>,
+    pub duplicates: usize,
+    pub trimmed: TrimCounts,
+    pub binned: (usize, usize),
+}
+
+impl FilterCounts {
+    pub fn add(&mut self, reasons: Vec<String>) {
+        self.pairs += 1;
+        for reason in reasons {
+            *self.reasons.entry(reason).or_default() += 1;
+        }
+    }
+
+    pub fn add_other(&mut self, other: &FilterCounts) {
+        self.pairs += other.pairs;
+        self.duplicates += other.duplicates;
+        self.trimmed.add_other(&other.trimmed);
+        self.binned.0 += other.binned.0;
+        self.binned.1 += other.binned.1;
+        for (reason, n) in other.reasons.iter() {
+            *self.reasons.entry(reason.clone()).or_default() += n;
+        }
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "filtered: {}", self.pairs)?;
+        for (reason, n) in self.reasons.iter() {
+            writeln!(out, "filtered_by: {}\t{}", reason, n)?;
+        }
+        writeln!(out, "duplicates: {}", self.duplicates)?;
+        self.trimmed.write(out)?;
+        if self.binned.1 > 0 {
+            writeln!(
+                out,
+                "binned_qualities: {}\t{}",
+                self.binned.0, self.binned.1
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Applied to both mates, so that a pair is kept or turned away as a whole.
+#[derive(Clone, Debug, Default)]
+pub struct PairFilter {
+    pub required: u16,
+    pub excluded: u16,
+    pub mapped: Option<Mates>,
+    // A missing mapping quality (255) counts as high, as with samtools -q.
+    pub min_mapq: Option<(u8, Mates)>,
+    // Keep pairs with a mate mapped to one of these.
+    pub include_contigs: Option<HashSet<usize>>,
+    // Drop pairs with a mate mapped to one of these.
+    pub exclude_contigs: HashSet<usize>,
+}
+
+impl PairFilter {
+    pub fn is_active(&self) -> bool {
+        self.required != 0
+            || self.excluded != 0
+            || self.mapped.is_some()
+            || self.min_mapq.is_some()
+            || self.include_contigs.is_some()
+            || !self.exclude_contigs.is_empty()
+    }
+
+    // Why the pair is turned away, or nothing if it is kept.
+    pub fn reasons(&self, pair: &(Record, Record)) -> Vec<String> {
+        let mut reasons = Vec::new();
+        if !self.is_active() {
+            return reasons;
+        }
+        let mut missing = 0;
+        let mut present = 0;
+        for rec in [&pair.0, &pair.1] {
+            let bits = rec.flags().bits();
+            missing |= self.required & !bits;
+            present |= self.excluded & bits;
+        }
+        for (name, bit) in FLAG_NAMES.iter() {
+            if missing & bit != 0 {
+                reasons.push(format!("-f {}", name));
+            }
+            if present & bit != 0 {
+                reasons.push(format!("-F {}", name));
+            }
+        }
+
+        let (r1, r2) = (&pair.0, &pair.1);
+        if let Some(mates) = self.mapped {
+            if !mates.holds(!r1.flags().is_unmapped(), !r2.flags().is_unmapped()) {
+                reasons.push("--mapped".to_string());
+            }
+        }
+        if let Some((min_mapq, mates)) = self.min_mapq {
+            let high =
+                |rec: &Record| rec.mapping_quality().map(|q| q.get()).unwrap_or(255) >= min_mapq;
+            if !mates.holds(high(r1), high(r2)) {
+                reasons.push("--min-mapq".to_string());
+            }
+        }
+        let contigs = [mapped_contig(r1), mapped_contig(r2)];
+        if let Some(include) = &self.include_contigs {
+            if !contigs.iter().flatten().any(|id| include.contains(id)) {
+                reasons.push("--include-contigs".to_string());
+            }
+        }
+        if contigs
+            .iter()
+            .flatten()
+            .any(|id| self.exclude_contigs.contains(id))
+        {
+            reasons.push("--exclude-contigs".to_string());
+        }
+        reasons
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::Write;
+
+use noodles::sam::record::Flags;
+
+use crate::pairer::MateOtherChr;
+
+// The categories of `samtools flagstat`, for either the QC-passed or the QC-failed reads.
+#[derive(Debug, Default)]
+struct Counts {
+    read: usize,
+    primary: usize,
+    secondary: usize,
+    supplementary: usize,
+    duplicate: usize,
+    primary_duplicate: usize,
+    mapped: usize,
+    primary_mapped: usize,
+    paired: usize,
+    read_1: usize,
+    read_2: usize,
+    proper_pair: usize,
+    mate_mapped: usize,
+    singleton: usize,
+    mate_other_chr: usize,
+    mate_other_chr_hq: usize,
+}
+
+impl Counts {
+    fn add(&mut self, flags: Flags, n: usize) {
+        self.read += n;
+        if !flags.is_unmapped() {
+            self.mapped += n;
+        }
+        if flags.is_duplicate() {
+            self.duplicate += n;
+        }
+        if flags.is_secondary() {
+            self.secondary += n;
+            return;
+        }
+        if flags.is_supplementary() {
+            self.supplementary += n;
+            return;
+        }
+        self.primary += n;
+        if !flags.is_unmapped() {
+            self.primary_mapped += n;
+        }
+        if flags.is_duplicate() {
+            self.primary_duplicate += n;
+        }
+        if !flags.is_segmented() {
+            return;
+        }
+        self.paired += n;
+        if flags.is_first_segment() {
+            self.read_1 += n;
+        }
+        if flags.is_last_segment() {
+            self.read_2 += n;
+        }
+        if !flags.is_unmapped() {
+            if flags.is_properly_aligned() {
+                self.proper_pair += n;
+            }
+            if flags.is_mate_unmapped() {
+                self.singleton += n;
+            } else {
+                self.mate_mapped += n;
+            }
+        }
+    }
+
+    fn lines(&self) -> Vec<(&'static str, usize, Option<usize>)> {
+        vec![
+            ("total", self.read, None),
+            ("primary", self.primary, None),
+            ("secondary", self.secondary, None),
+            ("supplementary", self.supplementary, None),
+            ("duplicates", self.duplicate, None),
+            ("primary duplicates", self.primary_duplicate, None),
+            ("mapped", self.mapped, Some(self.read)),
+            ("primary mapped", self.primary_mapped, Some(self.primary)),
+            ("paired in sequencing", self.paired, None),
+            ("read1", self.read_1, None),
+            ("read2", self.read_2, None),
+            ("properly paired", self.proper_pair, Some(self.paired)),
+            ("with itself and mate mapped", self.mate_mapped, None),
+            ("singletons", self.singleton, Some(self.paired)),
+            (
+                "with mate mapped to a different chr",
+                self.mate_other_chr,
+                None,
+            ),
+            (
+                "with mate mapped to a different chr (mapQ >= 5)",
+                self.mate_other_chr_hq,
+                None,
+            ),
+        ]
+    }
+}
+
+fn percentage(n: usize, total: usize) -> Option<f64> {
+    if total == 0 {
+        None
+    } else {
+        Some(n as f64 / total as f64 * 100.0)
+    }
+}
+
+pub struct FlagStat {
+    passed: Counts,
+    failed: Counts,
+}
+
+impl FlagStat {
+    pub fn new(flags: &[usize], mate_other_chr: &MateOtherChr) -> FlagStat {
+        let mut passed = Counts::default();
+        let mut failed = Counts::default();
+        for (bits, n) in flags.iter().enumerate() {
+            if *n == 0 {
+                continue;
+            }
+            let flags = Flags::from_bits_truncate(bits as u16);
+            if flags.is_qc_fail() {
+                failed.add(flags, *n);
+            } else {
+                passed.add(flags, *n);
+            }
+        }
+        (passed.mate_other_chr, passed.mate_other_chr_hq) = mate_other_chr[0];
+        (failed.mate_other_chr, failed.mate_other_chr_hq) = mate_other_chr[1];
+        FlagStat { passed, failed }
+    }
+
+    // The same layout as `samtools flagstat`.
+    pub fn write_text(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let format = |p: Option<f64>| match p {
+            None => "N/A".to_string(),
+            Some(p) => format!("{:.2}%", p),
+        };
+        let passed = self.passed.lines();
+        let failed = self.failed.lines();
+        for ((name, p, p_total), (_, f, f_total)) in passed.into_iter().zip(failed) {
+            match name {
+                "total" => writeln!(
+                    out,
+                    "{} + {} in total (QC-passed reads + QC-failed reads)",
+                    p, f
+                )?,
+                _ => match (p_total, f_total) {
+                    (Some(p_total), Some(f_total)) => writeln!(
+                        out,
+                        "{} + {} {} ({} : {})",
+                        p,
+                        f,
+                        name,
+                        format(percentage(p, p_total)),
+                        format(percentage(f, f_total))
+                    )?,
+                    _ => writeln!(out, "{} + {} {}", p, f, name.replace(" >= ", ">="))?,
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // The same keys as `samtools flagstat -O json`.
+    pub fn write_json(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let format = |p: Option<f64>| match p {
+            None => "null".to_string(),
+            Some(p) => format!("{:.2}", p),
+        };
+        writeln!(out, "{{")?;
+        let sections = [
+            ("QC-passed reads", &self.passed),
+            ("QC-failed reads", &self.failed),
+        ];
+        for (i, (section, counts)) in sections.iter().enumerate() {
+            writeln!(out, "  \"{}\": {{", section)?;
+            let mut items = Vec::new();
+            for (name, n, total) in counts.lines() {
+                items.push(format!("    \"{}\": {}", name, n));
+                if let Some(total) = total {
+                    items.push(format!(
+                        "    \"{} %\": {}",
+                        name,
+                        format(percentage(n, total))
+                    ));
+                }
+            }
+            writeln!(out, "{}", items.join(",\n"))?;
+            writeln!(out, "  }}{}", if i + 1 < sections.len() { "," } else { "" })?;
+        }
+        writeln!(out, "}}")
+    }
+}
@@ -1,105 +1,418 @@
-use std::io::Write;
+use std::{borrow::Cow, collections::HashMap, io::Write};
 
-use noodles::sam::{alignment::Record, record::sequence::Base};
+use noodles::sam::{
+    self,
+    alignment::Record,
+    record::{
+        cigar::op::Kind,
+        data::field::{tag, Tag},
+        sequence::Base,
+        Flags,
+    },
+};
 
-use crate::block_writer::LocalBlockPairWriter;
+use crate::{
+    binning::QualityBins,
+    block_writer::{LocalBlockPairWriter, PairEnd},
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputSplit {
+    Single,
+    ReadGroup,
+    Shards(usize),
+}
+
+// FNV-1a with a final mix so the low bits are usable; stable across runs and platforms.
+pub fn name_hash(name: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in name {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h
+}
+
+pub fn shard_name(shard: usize, shards: usize) -> String {
+    let width = format!("{}", shards.max(2) - 1).len();
+    format!("{:0width$}", shard, width = width)
+}
+
+// The output key for pairs without an RG tag when splitting by read group.
+pub const NO_READ_GROUP: &str = "unknown";
+
+pub fn read_group(rec: &Record) -> Option<&str> {
+    rec.data().get(&tag::READ_GROUP).and_then(|v| v.as_str())
+}
+
+// Where the UMI from the RX tag goes in the FASTQ.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UmiPlacement {
+    #[default]
+    None,
+    // After the read name, as name:UMI or name:CB:UMI, which is what UMI-tools expects.
+    Name,
+    // In front of R1's bases, with its qualities from QX.
+    Sequence,
+}
+
+impl UmiPlacement {
+    pub fn parse(txt: &str) -> std::io::Result<UmiPlacement> {
+        match txt {
+            "none" => Ok(UmiPlacement::None),
+            "name" => Ok(UmiPlacement::Name),
+            "sequence" => Ok(UmiPlacement::Sequence),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("unknown UMI placement: {}", txt),
+            )),
+        }
+    }
+}
+
+fn tag_str(rec: &Record, tag: Tag) -> Option<&str> {
+    rec.data().get(&tag).and_then(|v| v.as_str())
+}
+
+// Duplex UMIs are joined with hyphens, and their qualities with spaces. A hyphen in QX is
+// a quality (Q12), so each tag only loses its own separator.
+fn strip_separator(txt: &str, separator: char) -> String {
+    txt.chars().filter(|c| *c != separator).collect()
+}
+
+// How pairs are rendered, shared by every formatter so that a digest sees what the files would hold.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FormatOptions {
+    pub annotate_duplicates: bool,
+    pub lowercase_soft_clips: bool,
+    pub umi: UmiPlacement,
+    pub cell_barcode: bool,
+    pub quality_bins: Option<QualityBins>,
+}
+
+impl FormatOptions {
+    // The FASTQ header after the @: the read name, followed by any annotations.
+    pub fn read_id<'a>(&self, rec: &'a Record) -> Cow<'a, str> {
+        let name: &str = rec.read_name().unwrap().as_ref();
+        let mut read_id = Cow::Borrowed(name);
+        if self.umi == UmiPlacement::Name {
+            if let Some(umi) = tag_str(rec, tag::UMI_SEQUENCE) {
+                let barcode = tag_str(rec, tag::CELL_BARCODE_ID)
+                    .or_else(|| tag_str(rec, tag::CELL_BARCODE_SEQUENCE));
+                read_id = match barcode {
+                    Some(barcode) if self.cell_barcode => {
+                        Cow::Owned(format!("{}:{}:{}", name, barcode, umi))
+                    }
+                    _ => Cow::Owned(format!("{}:{}", name, umi)),
+                };
+            }
+        }
+        if self.annotate_duplicates && rec.flags().is_duplicate() {
+            read_id = Cow::Owned(format!("{} DUP", read_id));
+        }
+        read_id
+    }
+
+    fn prepends_umi(&self, rec: &Record) -> Option<String> {
+        if self.umi == UmiPlacement::Sequence && rec.flags().is_first_segment() {
+            tag_str(rec, tag::UMI_SEQUENCE).map(|umi| strip_separator(umi, '-'))
+        } else {
+            None
+        }
+    }
+
+    pub fn sequence(&self, rec: &Record) -> String {
+        let mut seq = rec.sequence().to_string();
+        if self.lowercase_soft_clips {
+            let (start, end) = soft_clip_lengths(rec);
+            let end = seq.len().saturating_sub(end);
+            seq[..start.min(end)].make_ascii_lowercase();
+            seq[end..].make_ascii_lowercase();
+        }
+        match self.prepends_umi(rec) {
+            Some(umi) => umi + &seq,
+            None => seq,
+        }
+    }
+
+    // UMI bases without qualities in QX are given I (Q40), but a QX that doesn't match the
+    // UMI is an error.
+    pub fn quality(&self, rec: &Record) -> std::io::Result<String> {
+        let qual = match &self.quality_bins {
+            Some(bins) => rec
+                .quality_scores()
+                .as_ref()
+                .iter()
+                .map(|q| (bins.bin(*q).get() + 33) as char)
+                .collect(),
+            None => rec.quality_scores().to_string(),
+        };
+        let Some(umi) = self.prepends_umi(rec) else {
+            return Ok(qual);
+        };
+        let umi_qual = match tag_str(rec, tag::UMI_QUALITY_SCORES) {
+            Some(umi_qual) => strip_separator(umi_qual, ' '),
+            None => "I".repeat(umi.len()),
+        };
+        if umi_qual.len() != umi.len() {
+            let name: &str = rec.read_name().map(|name| name.as_ref()).unwrap_or("*");
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "read {} has {} UMI bases in RX but {} qualities in QX",
+                    name,
+                    umi.len(),
+                    umi_qual.len()
+                ),
+            ));
+        }
+        Ok(umi_qual + &qual)
+    }
+
+    pub fn write_fastq_record(
+        &self,
+        buffer: &mut Vec<u8>,
+        read_id: &str,
+        rec: &Record,
+    ) -> std::io::Result<()> {
+        writeln!(buffer, "@{}", read_id)?;
+        writeln!(buffer, "{}", self.sequence(rec))?;
+        writeln!(buffer, "+")?;
+        writeln!(buffer, "{}", self.quality(rec)?)?;
+        Ok(())
+    }
+}
+
+// Soft clipped bases at the start and end of the stored sequence, looking past any hard clips.
+pub fn soft_clip_lengths(rec: &Record) -> (usize, usize) {
+    let is_clip = |kind: Kind| matches!(kind, Kind::SoftClip | Kind::HardClip);
+    let soft = |len: usize, kind: Kind| if kind == Kind::SoftClip { len } else { 0 };
+    let ops = rec.cigar().as_ref();
+    let start = ops
+        .iter()
+        .take_while(|op| is_clip(op.kind()))
+        .map(|op| soft(op.len(), op.kind()))
+        .sum();
+    let end = ops
+        .iter()
+        .rev()
+        .take_while(|op| is_clip(op.kind()))
+        .map(|op| soft(op.len(), op.kind()))
+        .sum();
+    (start, end)
+}
+
+// R1, R2 and sidecar lines for an output, and where each pair ends in them.
+type PairBuffers = (Vec<u8>, Vec<u8>, Vec<u8>, Vec<PairEnd>);
 
 pub struct ReadParFormatter {
-    buffers: (Vec<u8>, Vec<u8>),
+    split: OutputSplit,
+    options: FormatOptions,
+    buffers: HashMap<String, PairBuffers>,
+    size: usize,
     writers: LocalBlockPairWriter,
 }
 
 impl ReadParFormatter {
-    pub fn new(writers: LocalBlockPairWriter) -> ReadParFormatter {
+    pub fn new(
+        writers: LocalBlockPairWriter,
+        split: OutputSplit,
+        options: FormatOptions,
+    ) -> ReadParFormatter {
         ReadParFormatter {
-            buffers: (Vec::new(), Vec::new()),
+            split,
+            options,
+            buffers: HashMap::new(),
+            size: 0,
             writers,
         }
     }
 
-    pub fn write(&mut self, pair: (Record, Record)) -> std::io::Result<()> {
-        assert!(pair.0.flags().is_first_segment() || pair.0.flags().is_last_segment());
-        assert!(pair.1.flags().is_first_segment() || pair.1.flags().is_last_segment());
-        if pair.0.flags().is_first_segment() != !pair.1.flags().is_first_segment() {
-            println!("eek! {} {}", pair.0.flags().bits(), pair.1.flags().bits());
+    fn key(&self, r1: &Record) -> String {
+        match self.split {
+            OutputSplit::Single => String::new(),
+            OutputSplit::ReadGroup => read_group(r1).unwrap_or(NO_READ_GROUP).to_string(),
+            OutputSplit::Shards(shards) => {
+                let name: &[u8] = r1.read_name().unwrap().as_ref();
+                shard_name((name_hash(name) % shards as u64) as usize, shards)
+            }
         }
-        assert_eq!(
-            pair.0.flags().is_first_segment(),
-            !pair.1.flags().is_first_segment()
-        );
-        assert_eq!(
-            pair.0.flags().is_last_segment(),
-            !pair.1.flags().is_last_segment()
-        );
+    }
 
-        let (mut r1, mut r2) = if pair.0.flags().is_first_segment() {
-            pair
-        } else {
-            (pair.1, pair.0)
-        };
+    pub fn write(&mut self, pair: (Record, Record), origin: Option<&str>) -> std::io::Result<()> {
+        let (r1, r2) = orient_pair(pair);
 
-        if r1.flags().is_reverse_complemented() {
-            reverse_complement(&mut r1);
-        }
+        let key = self.key(&r1);
+        let interleaved = self.writers.is_interleaved();
+        let buffers = self.buffers.entry(key).or_default();
+        let before = buffers.0.len() + buffers.1.len() + buffers.2.len();
 
-        if r2.flags().is_reverse_complemented() {
-            reverse_complement(&mut r2);
+        if let Some(origin) = origin {
+            writeln!(buffers.2, "{}", origin)?;
         }
 
-        let read_id: &str = r1.read_name().unwrap().as_ref();
+        let read_id = self.options.read_id(&r1);
 
-        writeln!(&mut self.buffers.0, "@{}", read_id)?;
-        writeln!(&mut self.buffers.0, "{}", r1.sequence())?;
-        writeln!(&mut self.buffers.0, "+")?;
-        writeln!(&mut self.buffers.0, "{}", r1.quality_scores())?;
+        self.options
+            .write_fastq_record(&mut buffers.0, &read_id, &r1)?;
 
-        writeln!(&mut self.buffers.1, "@{}", read_id)?;
-        writeln!(&mut self.buffers.1, "{}", r2.sequence())?;
-        writeln!(&mut self.buffers.1, "+")?;
-        writeln!(&mut self.buffers.1, "{}", r2.quality_scores())?;
+        let buffer_2 = if interleaved {
+            &mut buffers.0
+        } else {
+            &mut buffers.1
+        };
+        self.options.write_fastq_record(buffer_2, &read_id, &r2)?;
+
+        buffers
+            .3
+            .push((buffers.0.len(), buffers.1.len(), buffers.2.len()));
+        self.size += buffers.0.len() + buffers.1.len() + buffers.2.len() - before;
 
-        if self.buffers.0.len() + self.buffers.1.len() > 16 * 1024 * 1024 {
-            self.writers.write((&self.buffers.0, &self.buffers.1))?;
-            self.buffers.0.clear();
-            self.buffers.1.clear();
+        if self.size > 16 * 1024 * 1024 {
+            self.flush()?;
         }
 
         Ok(())
     }
 
     pub fn flush(&mut self) -> std::io::Result<()> {
-        if self.buffers.0.len() + self.buffers.1.len() > 0 {
-            self.writers.write((&self.buffers.0, &self.buffers.1))?;
-            self.buffers.0.clear();
-            self.buffers.1.clear();
+        for (key, buffers) in self.buffers.iter_mut() {
+            if !buffers.3.is_empty() {
+                self.writers
+                    .write(key, (&buffers.0, &buffers.1), &buffers.2, &buffers.3)?;
+                buffers.0.clear();
+                buffers.1.clear();
+                buffers.2.clear();
+                buffers.3.clear();
+            }
         }
+        self.size = 0;
 
         Ok(())
     }
 }
 
-fn reverse_complement(rec: &mut Record) {
-    fn complement(base: Base) -> Base {
-        match base {
-            Base::Eq => Base::Eq,
-            Base::A => Base::T,
-            Base::C => Base::G,
-            Base::M => Base::K,
-            Base::G => Base::C,
-            Base::R => Base::Y,
-            Base::S => Base::S,
-            Base::V => Base::B,
-            Base::T => Base::A,
-            Base::W => Base::W,
-            Base::Y => Base::R,
-            Base::H => Base::D,
-            Base::K => Base::M,
-            Base::D => Base::H,
-            Base::B => Base::V,
-            _ => Base::N,
-        }
+pub trait PairFormatter {
+    fn write(&mut self, pair: (Record, Record)) -> std::io::Result<()>;
+    // Outputs without a sidecar have nowhere to put the line, and drop it.
+    fn write_with_origin(&mut self, pair: (Record, Record), _origin: &str) -> std::io::Result<()> {
+        self.write(pair)
+    }
+    fn flush(&mut self) -> std::io::Result<()>;
+}
+
+impl PairFormatter for ReadParFormatter {
+    fn write(&mut self, pair: (Record, Record)) -> std::io::Result<()> {
+        ReadParFormatter::write(self, pair, None)
+    }
+
+    fn write_with_origin(&mut self, pair: (Record, Record), origin: &str) -> std::io::Result<()> {
+        ReadParFormatter::write(self, pair, Some(origin))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        ReadParFormatter::flush(self)
+    }
+}
+
+// A sidecar line giving the name of a pair and where its mates were aligned, as R1 then R2
+// reference, position, mapping quality, CIGAR and flags, with SAM's placeholders for the missing.
+pub fn origin_line(header: &sam::Header, pair: &(Record, Record)) -> String {
+    let (r1, r2) = if pair.0.flags().is_first_segment() {
+        (&pair.0, &pair.1)
+    } else {
+        (&pair.1, &pair.0)
+    };
+    let mut line: String = r1
+        .read_name()
+        .map(|name| name.as_ref())
+        .unwrap_or("*")
+        .to_string();
+    for rec in [r1, r2] {
+        let reference = rec
+            .reference_sequence_id()
+            .and_then(|id| header.reference_sequences().get_index(id))
+            .map(|(name, _)| name.to_string())
+            .unwrap_or_else(|| "*".to_string());
+        let position = rec.alignment_start().map(|p| p.get()).unwrap_or(0);
+        let mapq = rec.mapping_quality().map(|q| q.get()).unwrap_or(255);
+        let cigar = if rec.cigar().is_empty() {
+            "*".to_string()
+        } else {
+            rec.cigar().to_string()
+        };
+        line += &format!(
+            "\t{}\t{}\t{}\t{}\t{}",
+            reference,
+            position,
+            mapq,
+            cigar,
+            rec.flags().bits()
+        );
+    }
+    line
+}
+
+pub fn orient_pair(pair: (Record, Record)) -> (Record, Record) {
+    assert!(pair.0.flags().is_first_segment() || pair.0.flags().is_last_segment());
+    assert!(pair.1.flags().is_first_segment() || pair.1.flags().is_last_segment());
+    assert_eq!(
+        pair.0.flags().is_first_segment(),
+        !pair.1.flags().is_first_segment(),
+        "mates with flags {} and {} are not one R1 and one R2",
+        pair.0.flags().bits(),
+        pair.1.flags().bits()
+    );
+    assert_eq!(
+        pair.0.flags().is_last_segment(),
+        !pair.1.flags().is_last_segment()
+    );
+
+    let (r1, r2) = if pair.0.flags().is_first_segment() {
+        pair
+    } else {
+        (pair.1, pair.0)
+    };
+
+    (orient_read(r1), orient_read(r2))
+}
+
+// Back to the orientation in which the read was sequenced. The CIGAR is reversed to keep up
+// with the bases, and the flag is cleared, so orienting a read twice leaves it as it was.
+pub fn orient_read(mut rec: Record) -> Record {
+    if rec.flags().is_reverse_complemented() {
+        reverse_complement(&mut rec);
+        rec.cigar_mut().as_mut().reverse();
+        rec.flags_mut().remove(Flags::REVERSE_COMPLEMENTED);
     }
+    rec
+}
+
+pub fn complement(base: Base) -> Base {
+    match base {
+        Base::Eq => Base::Eq,
+        Base::A => Base::T,
+        Base::C => Base::G,
+        Base::M => Base::K,
+        Base::G => Base::C,
+        Base::R => Base::Y,
+        Base::S => Base::S,
+        Base::V => Base::B,
+        Base::T => Base::A,
+        Base::W => Base::W,
+        Base::Y => Base::R,
+        Base::H => Base::D,
+        Base::K => Base::M,
+        Base::D => Base::H,
+        Base::B => Base::V,
+        _ => Base::N,
+    }
+}
 
+fn reverse_complement(rec: &mut Record) {
     let seq = rec.sequence_mut();
     seq.as_mut().reverse();
     for base in seq.as_mut() {
@@ -109,3 +422,58 @@ fn reverse_complement(rec: &mut Record) {
     let qual = rec.quality_scores_mut();
     qual.as_mut().reverse();
 }
+
+#[cfg(test)]
+mod tests {
+    use noodles::sam::record::{data::field::Value, Data, QualityScores, ReadName, Sequence};
+
+    use super::*;
+
+    fn read_1(umi: &str, umi_qual: Option<&str>) -> Record {
+        let mut data = Data::default();
+        data.insert(tag::UMI_SEQUENCE, Value::String(umi.to_string()));
+        if let Some(umi_qual) = umi_qual {
+            data.insert(tag::UMI_QUALITY_SCORES, Value::String(umi_qual.to_string()));
+        }
+        Record::builder()
+            .set_read_name("r".parse::<ReadName>().unwrap())
+            .set_flags(Flags::SEGMENTED | Flags::FIRST_SEGMENT)
+            .set_sequence("ACGT".parse::<Sequence>().unwrap())
+            .set_quality_scores("IIII".parse::<QualityScores>().unwrap())
+            .set_data(data)
+            .build()
+    }
+
+    fn in_sequence() -> FormatOptions {
+        FormatOptions {
+            umi: UmiPlacement::Sequence,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn umi_qualities_keep_hyphens() {
+        let rec = read_1("ACG", Some("-5F"));
+        assert_eq!(in_sequence().sequence(&rec), "ACGACGT");
+        assert_eq!(in_sequence().quality(&rec).unwrap(), "-5FIIII");
+    }
+
+    #[test]
+    fn duplex_umis_lose_their_separators() {
+        let rec = read_1("AC-GT", Some("-5 F#"));
+        assert_eq!(in_sequence().sequence(&rec), "ACGTACGT");
+        assert_eq!(in_sequence().quality(&rec).unwrap(), "-5F#IIII");
+    }
+
+    #[test]
+    fn missing_umi_qualities() {
+        let rec = read_1("ACG", None);
+        assert_eq!(in_sequence().quality(&rec).unwrap(), "IIIIIII");
+    }
+
+    #[test]
+    fn mismatched_umi_qualities() {
+        let rec = read_1("ACG", Some("-5"));
+        assert!(in_sequence().quality(&rec).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::Write;
+
+use noodles::sam;
+
+use crate::files::open_writer;
+
+// The header lines that FASTQ has no room for and realignment wants back: read groups,
+// program history and comments, as SAM text in the order the header holds them.
+pub fn provenance_lines(header: &sam::Header) -> Vec<String> {
+    header
+        .to_string()
+        .lines()
+        .filter(|line| {
+            line.starts_with("@RG\t") || line.starts_with("@PG\t") || line.starts_with("@CO\t")
+        })
+        .map(|line| line.to_string())
+        .collect()
+}
+
+// Each read group's ID, and the line to give an aligner for it, with its tabs written as \t
+// the way bwa -R takes them.
+pub fn read_group_args(header: &sam::Header) -> Vec<(String, String)> {
+    header
+        .read_groups()
+        .iter()
+        .map(|(id, rg)| {
+            (
+                id.clone(),
+                format!("@RG\\tID:{}{}", id, rg).replace('\t', "\\t"),
+            )
+        })
+        .collect()
+}
+
+pub fn write_provenance(filename: &str, header: &sam::Header) -> std::io::Result<()> {
+    let mut out = open_writer(filename)?;
+    for line in provenance_lines(header) {
+        writeln!(out, "{}", line)?;
+    }
+    out.flush()
+}
+
+pub fn write_read_group_args(filename: &str, header: &sam::Header) -> std::io::Result<()> {
+    let mut out = open_writer(filename)?;
+    writeln!(out, "id\targ")?;
+    for (id, arg) in read_group_args(header) {
+        writeln!(out, "{}\t{}", id, arg)?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> sam::Header {
+        "@HD\tVN:1.6\tSO:coordinate\n\
+         @SQ\tSN:chr1\tLN:1000\n\
+         @RG\tID:rg1\tSM:sample 1\tPL:ILLUMINA\tLB:lib1\tPU:flowcell.1\n\
+         @RG\tID:rg2\tSM:sample2\n\
+         @PG\tID:bwa\tPN:bwa\tCL:bwa mem ref.fa r1.fq r2.fq\n\
+         @CO\tfrom the sequencer\n"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn read_group_args_escape_tabs() {
+        let args = read_group_args(&header());
+        assert_eq!(
+            args,
+            vec![
+                (
+                    "rg1".to_string(),
+                    "@RG\\tID:rg1\\tLB:lib1\\tPL:ILLUMINA\\tPU:flowcell.1\\tSM:sample 1"
+                        .to_string()
+                ),
+                ("rg2".to_string(), "@RG\\tID:rg2\\tSM:sample2".to_string()),
+            ]
+        );
+        assert!(args.iter().all(|(_, arg)| !arg.contains('\t')));
+    }
+
+    #[test]
+    fn provenance_leaves_out_the_rest_of_the_header() {
+        assert_eq!(
+            provenance_lines(&header()),
+            vec![
+                "@RG\tID:rg1\tLB:lib1\tPL:ILLUMINA\tPU:flowcell.1\tSM:sample 1",
+                "@RG\tID:rg2\tSM:sample2",
+                "@PG\tID:bwa\tPN:bwa\tCL:bwa mem ref.fa r1.fq r2.fq",
+                "@CO\tfrom the sequencer",
+            ]
+        );
+    }
+
+    #[test]
+    fn sidecar_files() {
+        let dir = std::env::temp_dir().join(format!("mazab-header-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let provenance = dir.join("provenance.txt").to_string_lossy().to_string();
+        let rg_args = dir.join("rg_args.tsv").to_string_lossy().to_string();
+        write_provenance(&provenance, &header()).unwrap();
+        write_read_group_args(&rg_args, &header()).unwrap();
+        let provenance = std::fs::read_to_string(&provenance).unwrap();
+        assert_eq!(provenance.lines().count(), 4);
+        assert!(provenance.ends_with("@CO\tfrom the sequencer\n"));
+        assert_eq!(
+            std::fs::read_to_string(&rg_args).unwrap(),
+            "id\targ\n\
+             rg1\t@RG\\tID:rg1\\tLB:lib1\\tPL:ILLUMINA\\tPU:flowcell.1\\tSM:sample 1\n\
+             rg2\t@RG\\tID:rg2\\tSM:sample2\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
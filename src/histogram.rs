@@ -0,0 +1,107 @@
+use std::io::Write;
+
+use noodles::sam::{alignment::Record, record::sequence::Base};
+
+use crate::summarise::Summariser;
+
+// Template lengths at or beyond this are counted in the last bin.
+pub const MAX_TEMPLATE_LENGTH: usize = 10_000;
+
+// Library QC distributions over the primary reads that go on to be paired.
+#[derive(Clone, Debug, Default)]
+pub struct Histograms {
+    pub template_length: Vec<usize>,
+    pub read_length: Vec<usize>,
+    pub mean_quality: Vec<usize>,
+    pub gc: Vec<usize>,
+}
+
+fn bump(bins: &mut Vec<usize>, i: usize) {
+    if bins.len() <= i {
+        bins.resize(i + 1, 0);
+    }
+    bins[i] += 1;
+}
+
+fn add_bins(bins: &mut Vec<usize>, other: &[usize]) {
+    if bins.len() < other.len() {
+        bins.resize(other.len(), 0);
+    }
+    for (bin, n) in bins.iter_mut().zip(other) {
+        *bin += *n;
+    }
+}
+
+pub fn write_summary(out: &mut dyn Write, name: &str, summary: &Summariser) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "summary: {}\t{}\t{:.2}\t{:.2}\t{}\t{:.2}\t{}",
+        name,
+        summary.n,
+        summary.mean(),
+        summary.sd(),
+        summary.min(),
+        summary.median(),
+        summary.max()
+    )
+}
+
+impl Histograms {
+    pub fn new() -> Histograms {
+        Histograms::default()
+    }
+
+    pub fn add(&mut self, rec: &Record) {
+        // Each pair has one positive and one negative template length, so it is counted once.
+        let tlen = rec.template_length();
+        if tlen > 0 {
+            bump(
+                &mut self.template_length,
+                (tlen as usize).min(MAX_TEMPLATE_LENGTH),
+            );
+        }
+
+        let seq = rec.sequence().as_ref();
+        bump(&mut self.read_length, seq.len());
+        if !seq.is_empty() {
+            let gc = seq
+                .iter()
+                .filter(|b| matches!(b, Base::G | Base::C | Base::S))
+                .count();
+            bump(&mut self.gc, (100 * gc + seq.len() / 2) / seq.len());
+        }
+
+        let qual = rec.quality_scores().as_ref();
+        if !qual.is_empty() {
+            let total: usize = qual.iter().map(|q| q.get() as usize).sum();
+            bump(&mut self.mean_quality, total / qual.len());
+        }
+    }
+
+    pub fn add_other(&mut self, other: &Histograms) {
+        add_bins(&mut self.template_length, &other.template_length);
+        add_bins(&mut self.read_length, &other.read_length);
+        add_bins(&mut self.mean_quality, &other.mean_quality);
+        add_bins(&mut self.gc, &other.gc);
+    }
+
+    pub fn write(&self, out: &mut dyn Write, name: &str) -> std::io::Result<()> {
+        let kinds: [(&str, &[usize]); 4] = [
+            ("tlen", &self.template_length),
+            ("read_length", &self.read_length),
+            ("mean_qual", &self.mean_quality),
+            ("gc", &self.gc),
+        ];
+        for (kind, bins) in kinds.iter() {
+            let mut summary = Summariser::new();
+            for (value, count) in bins.iter().enumerate() {
+                if *count > 0 {
+                    writeln!(out, "hist: {}\t{}\t{}\t{}", name, kind, value, count)?;
+                }
+                summary.add_multiple(value as f64, *count);
+            }
+            write_summary(out, &format!("{}\t{}", name, kind), &summary)?;
+        }
+        Ok(())
+    }
+}
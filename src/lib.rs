@@ -6,4 +6,15 @@ pub mod pairer;
 pub mod formatter;
 pub mod block_writer;
 pub mod either;
-pub mod summarise;
\ No newline at end of file
+pub mod summarise;
+pub mod ubam;
+pub mod sketch;
+pub mod flagstat;
+pub mod histogram;
+pub mod filter;
+pub mod orphans;
+pub mod duplicates;
+pub mod trim;
+pub mod binning;
+pub mod sorter;
+pub mod header;
@@ -1,15 +1,34 @@
 use docopt::Docopt;
 use flate2::Compression;
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
-use mazab::block_writer::{BlockPairWriter, LocalBlockPairWriter};
-use mazab::pairer::Remainder;
+use mazab::binning::QualityBins;
+use mazab::block_writer::{BlockPairWriter, OutputCounts, OutputLimits};
+use mazab::duplicates::{is_duplicate, mark_duplicate, DuplicateDetector, DuplicatePolicy};
+use mazab::filter::{contig_ids, parse_flags, parse_names, FilterCounts, Mates, PairFilter};
+use mazab::flagstat::FlagStat;
+use mazab::formatter::{
+    orient_pair, origin_line, shard_name, FormatOptions, OutputSplit, PairFormatter, UmiPlacement,
+    NO_READ_GROUP,
+};
+use mazab::header::{write_provenance, write_read_group_args};
+use mazab::histogram::{write_summary, Histograms};
+use mazab::orphans::OrphanWriter;
+use mazab::pairer::{MateOtherChr, Remainder};
+use mazab::sorter::NameSorter;
 use mazab::summarise::Summariser;
+use mazab::trim::{clip_soft_clips, parse_adapters, SoftClips, Trimmer};
+use mazab::ubam::{parse_tags, unaligned_header, UnalignedBamFormatter, UnalignedBamWriter};
 use mazab::{
-    checksum::compute_checksum, files::open_writer, formatter::ReadParFormatter, pairer::Pairer,
+    checksum::{fastq_digest, Checksum, DigestFormatter, DigestMode, PairDigest},
+    files::open_writer,
+    formatter::ReadParFormatter,
+    pairer::Pairer,
     shuffler::Shuffler,
+    sketch::Sketch,
 };
 use noodles::core::Region;
-use noodles::{bam, sam::alignment::Record};
+use noodles::sam::record::data::field::Tag;
+use noodles::{bam, sam, sam::alignment::Record};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::{
     io::Write,
@@ -18,8 +37,13 @@ use std::{
 use threadpool::ThreadPool;
 
 const USAGE: &'static str = "
-Usage: mazab [options] <bam> <fastq1> <fastq2>
+Usage: mazab verify [options] <bam> <fastq1> <fastq2>
+       mazab compare [options] <fastq1> <fastq2> <other1> <other2>
+       mazab compare-sketches [options] <sketch>...
+       mazab -B [options] <bam> <ubam>
        mazab -X [options] <fastq1> <fastq2>
+       mazab -X [options] <bam>
+       mazab [options] <bam> <fastq1> <fastq2>
 
 Options:
     -h                      Show this help message.
@@ -27,7 +51,105 @@ Options:
     -C COMPRESSION          Level of gzip compression (0-9, none, fast, default, best) [default: default]
     -t THREADS              Number of additional threads to used [default: 4]
     -U                      Write the read IDs of unpaired reads to stdout.
-    -X                      Compute an order-independent digest on the reads.
+    -X                      Compute an order-independent digest on the reads, either from a pair of
+                            FASTQ files or directly from a BAM as it would be written to FASTQ.
+    --json                  Write the -X digest, pair count, mismatched names, truncation and sketch
+                            hashes as JSON. Mismatched names or truncated input are an error either way.
+    --digest MODE           What the -X, verify and compare digests cover: full (the FASTQ records as
+                            written), seq-qual (sequences and qualities), seq (sequences only) or
+                            norm-name (records with comments and /1 and /2 stripped from the names)
+                            [default: full]
+    -k K                    Keep the K smallest pair hashes in the sketch [default: 1000]
+    --sketch FILE           Save the -X sketch to FILE for use with compare-sketches.
+    -B                      Write an unaligned BAM rather than a pair of FASTQ files.
+    -T TAGS                 Comma separated list of tags to keep in unaligned BAM output [default: RG,BC,QT,RX,QX]
+    -R                      Write a FASTQ pair per read group, treating <fastq1> and <fastq2> as
+                            filename templates containing {rg} (e.g. {rg}_R1.fastq.gz).
+    --shards N              Spread pairs over N FASTQ pairs by a hash of the read name, treating
+                            <fastq1> and <fastq2> as filename templates containing {shard} [default: 0]
+    --max-pairs N           Roll over to a new FASTQ pair before a chunk would hold more than N pairs,
+                            treating <fastq1> and <fastq2> as filename templates containing {chunk}
+                            [default: 0]
+    --max-bytes SIZE        Roll over to a new FASTQ pair before any file of a chunk would exceed SIZE
+                            bytes, unless a single pair is bigger than that on its own (suffixes K, M
+                            and G are accepted) [default: 0]
+    --manifest FILE         Write a TSV manifest of the FASTQ files produced and their pair counts.
+    --flagstat FILE         Write samtools flagstat style statistics on the BAM records to FILE.
+    --flagstat-json FILE    Write the same statistics as JSON, laid out like samtools flagstat -O json.
+    --histograms            Add per-chromosome and overall histograms of template length, read length,
+                            mean base quality and GC percentage of the paired reads to the report,
+                            each followed by a summary line (n, mean, sd, min, median, max).
+    --header-sidecar FILE   Write the @RG, @PG and @CO lines of the BAM header to FILE, for putting
+                            back into the header after realignment.
+    --rg-args FILE          Write a TSV of each read group's ID and the @RG line to give an aligner
+                            for it, with tabs written as \\t as bwa -R takes them.
+    -f FLAGS                Only keep pairs where both mates have all of FLAGS set, given as a number
+                            or as names like samtools uses (e.g. PROPER_PAIR) [default: 0]
+    -F FLAGS                Drop pairs where either mate has any of FLAGS set (e.g. QCFAIL,DUP)
+                            [default: 0]
+    --orphans FILE          Write reads left without a mate, and both mates of pairs dropped by the
+                            filters, to FILE as single-end FASTQ, compressed as set by -C.
+    --duplicates POLICY     What to do with pairs where either mate is marked as a duplicate: keep,
+                            drop, or annotate them by adding DUP to the FASTQ headers (or keeping the
+                            flag in unaligned BAM output) [default: keep]
+    --detect-duplicates     Also mark pairs as duplicates when their mates' unclipped 5' positions and
+                            strands match an earlier pair's in the same read group, for BAMs that
+                            haven't been through duplicate marking. Only pairs the filters keep
+                            are compared.
+    --trim-adapters         Trim adapter read-through found from the overlap of the mates, and any of
+                            the --adapters found at the 3' ends of the reads.
+    --adapters SEQS         Comma separated adapter sequences for --trim-adapters (TruSeq and Nextera
+                            by default) [default: AGATCGGAAGAGC,CTGTCTCTTATACACATCT]
+    --trim-quality Q        Trim the 3' ends of the reads back to where the base quality reaches Q, as
+                            bwa -q does.
+    --trim-ns               Trim Ns from both ends of the reads.
+    --soft-clips MODE       What to do with soft clipped bases: keep them, remove them, or write them in
+                            lowercase or mask them with N. Lowercase is only possible in FASTQ
+                            [default: keep]
+    --umi WHERE             Put the UMI from the RX tag after the read name as name:UMI (name), or in
+                            front of R1's bases with its qualities from QX, or I without one
+                            (sequence), for FASTQ output. A QX that doesn't match RX is an error
+                            [default: none]
+    --cell-barcode          With --umi name, put the cell barcode from the CB (or CR) tag before the
+                            UMI as name:CB:UMI.
+    --bin-qualities BINS    Bin base qualities as they are written: illumina8, illumina4, or a table
+                            such as 0-9:6,10-19:15,20-93:37 of ranges that don't overlap, given
+                            inline or in a file. The digest of a BAM is taken over the binned
+                            qualities too.
+    --min-length N          Send pairs with a mate shorter than N after trimming to the orphan output,
+                            writing only the mates that are long enough. Reads trimmed to nothing are
+                            always sent [default: 0]
+    --sort-by-name          Write the pairs sorted by read name (in byte order) rather than shuffled,
+                            sorting runs of pairs on disk and merging them once all are read. The pair
+                            handling and writing of the merged pairs happens on a single thread.
+    --sort-pairs N          How many pairs each thread holds in memory before sorting them into a run
+                            for --sort-by-name (suffixes K, M and G are accepted) [default: 1M]
+    --tmp-dir DIR           Where --sort-by-name keeps its runs (the system temporary directory by
+                            default).
+    --sidecar FILE          Write a TSV line for each pair written, in the same order as the FASTQ, with
+                            the read name and then the reference, position, mapping quality, CIGAR and
+                            flags of R1 and R2 as they were in the BAM. Compressed as set by -C, and
+                            split like the FASTQ files when they are, given the same placeholders.
+    --mapped WHICH          Only keep pairs where both, either, one or neither of the mates are mapped.
+    --min-mapq Q            Only keep pairs where the mates given by --mapq-mates have a mapping
+                            quality of at least Q.
+    --mapq-mates WHICH      Whether --min-mapq applies to both, either, one or neither of the mates
+                            [default: both]
+    --include-contigs LIST  Only keep pairs with a mate mapped to one of these contigs, given as a
+                            comma separated list or a file with one name per line.
+    --exclude-contigs LIST  Drop pairs with a mate mapped to any of these contigs (e.g. decoys, chrM).
+
+verify digests <bam> as it would be written to FASTQ and compares it with <fastq1> and <fastq2>,
+exiting with an error and listing a sample of the reads found on only one side if they differ.
+
+compare digests two FASTQ pairs and reports whether they hold the same read pairs in any order,
+estimating their Jaccard similarity from the sketches and exiting with an error if they differ.
+
+compare-sketches estimates the Jaccard similarity and the containment of each sample in the other
+for every pair of saved sketches.
+
+Giving - for both <fastq1> and <fastq2> writes interleaved FASTQ to stdout. Existing named pipes
+are written to in place, so mazab can feed an aligner directly.
 ";
 
 pub fn make_compression(txt: &str) -> std::io::Result<Compression> {
@@ -51,7 +173,23 @@ pub fn make_compression(txt: &str) -> std::io::Result<Compression> {
     }
 }
 
-pub fn gather_chromosome_info(bam: &str) -> std::io::Result<(Vec<String>, Vec<usize>, Vec<usize>)> {
+pub fn make_size(txt: &str) -> std::io::Result<usize> {
+    let (num, scale) = match txt.chars().last() {
+        Some('k') | Some('K') => (&txt[..txt.len() - 1], 1 << 10),
+        Some('m') | Some('M') => (&txt[..txt.len() - 1], 1 << 20),
+        Some('g') | Some('G') => (&txt[..txt.len() - 1], 1 << 30),
+        _ => (txt, 1),
+    };
+    let n = num
+        .parse::<usize>()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "invalid size specifier"))?;
+    Ok(n * scale)
+}
+
+// The names, lengths and record counts of the references, then "*", along with the header.
+pub type ChromosomeInfo = (Vec<String>, Vec<usize>, Vec<usize>, sam::Header);
+
+pub fn gather_chromosome_info(bam: &str) -> std::io::Result<ChromosomeInfo> {
     let mut reader = bam::indexed_reader::Builder::default().build_from_path(bam)?;
     let header = reader.read_header()?;
     let mut chrom_names: Vec<String> = Vec::new();
@@ -97,11 +235,27 @@ pub fn gather_chromosome_info(bam: &str) -> std::io::Result<(Vec<String>, Vec<us
         println!("{:?}", chrom_record_count.len());
         panic!();
     }
-    Ok((chrom_names, chrom_lengths, chrom_record_count))
+    Ok((chrom_names, chrom_lengths, chrom_record_count, header))
+}
+
+// A BAM along with what its header and index say about it. It is opened before anything else
+// is, so that a BAM that can't be read is reported before any output is created.
+pub struct BamInput {
+    pub filename: String,
+    pub info: ChromosomeInfo,
+}
+
+impl BamInput {
+    pub fn open(filename: &str) -> std::io::Result<BamInput> {
+        Ok(BamInput {
+            filename: filename.to_string(),
+            info: gather_chromosome_info(filename)?,
+        })
+    }
 }
 
 pub fn chromosome_ranges(bam: &str) -> std::io::Result<Vec<String>> {
-    let (chrom_names, chrom_lengths, _chrom_record_count) = gather_chromosome_info(bam)?;
+    let (chrom_names, chrom_lengths, _chrom_record_count, _header) = gather_chromosome_info(bam)?;
     println!("{}", chrom_names.len());
     let mut res = Vec::new();
     res.push("*".to_string());
@@ -129,25 +283,101 @@ pub fn open_writers(
     Ok(Arc::new(Mutex::new((w1, w2))))
 }
 
+pub enum Output {
+    Fastq(BlockPairWriter, OutputSplit),
+    UnalignedBam(UnalignedBamWriter, Vec<Tag>),
+    Digest(Arc<Mutex<PairDigest>>, DigestMode),
+}
+
+impl Output {
+    pub fn formatter(
+        &self,
+        id: &str,
+        options: &FormatOptions,
+    ) -> std::io::Result<Box<dyn PairFormatter + Send>> {
+        match self {
+            Output::Fastq(writers, split) => Ok(Box::new(ReadParFormatter::new(
+                writers.writers(id)?,
+                *split,
+                *options,
+            ))),
+            Output::UnalignedBam(writer, tags) => Ok(Box::new(UnalignedBamFormatter::new(
+                writer.writer(id)?,
+                tags,
+                *options,
+            ))),
+            Output::Digest(total, mode) => Ok(Box::new(DigestFormatter::new(
+                total.clone(),
+                *mode,
+                *options,
+            ))),
+        }
+    }
+
+    pub fn finish(&mut self) -> std::io::Result<OutputCounts> {
+        match self {
+            Output::Fastq(writers, _split) => writers.finish(),
+            Output::UnalignedBam(writer, _tags) => {
+                writer.finish()?;
+                Ok(Vec::new())
+            }
+            Output::Digest(_total, _mode) => Ok(Vec::new()),
+        }
+    }
+
+    pub fn uses_stdout(&self) -> bool {
+        match self {
+            Output::Fastq(writers, _split) => writers.is_interleaved(),
+            Output::UnalignedBam(_writer, _tags) => false,
+            // The digest report follows on stdout.
+            Output::Digest(_total, _mode) => true,
+        }
+    }
+
+    pub fn is_split(&self) -> bool {
+        match self {
+            Output::Fastq(_writers, split) => *split != OutputSplit::Single,
+            Output::UnalignedBam(_writer, _tags) => false,
+            Output::Digest(_total, _mode) => false,
+        }
+    }
+}
+
+pub fn write_manifest(filename: &str, outputs: &OutputCounts) -> std::io::Result<()> {
+    let mut out = open_writer(filename)?;
+    writeln!(out, "key\tchunk\tfastq1\tfastq2\tpairs")?;
+    for output in outputs.iter() {
+        if let Some(filenames) = &output.filenames {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}",
+                output.key, output.chunk, filenames.0, filenames.1, output.count
+            )?;
+        }
+    }
+    out.flush()
+}
+
 pub fn make_ok(rec: Record) -> std::io::Result<Record> {
     Ok(rec)
 }
 
 pub fn make_chan() -> (
-    Option<Sender<(usize, Remainder)>>,
-    Receiver<(usize, Remainder)>,
+    Option<Sender<(usize, std::io::Result<Remainder>)>>,
+    Receiver<(usize, std::io::Result<Remainder>)>,
 ) {
     let (tx, rx) = channel();
     (Some(tx), rx)
 }
 
-pub fn print_flags(prefix: &str, flags: &[usize]) {
-    println!("{}flags: bits\tcount\tPAIRED\tPROPER\tUNMAP\tMUNMAP\tREVERSE\tMREVERSE\tREAD1\tREAD2\tSECONDARY\tQCFAIL\tDUP\tSUPPLEMENTARY", prefix);
+pub fn print_flags(out: &mut dyn Write, prefix: &str, flags: &[usize]) -> std::io::Result<()> {
+    writeln!(out, "{}flags: bits\tcount\tPAIRED\tPROPER\tUNMAP\tMUNMAP\tREVERSE\tMREVERSE\tREAD1\tREAD2\tSECONDARY\tQCFAIL\tDUP\tSUPPLEMENTARY", prefix)?;
     for i in 0..flags.len() {
         if flags[i] == 0 {
             continue;
         }
-        println!(
+        writeln!(
+            out,
             "{}flags: {}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
             prefix,
             i,
@@ -164,57 +394,208 @@ pub fn print_flags(prefix: &str, flags: &[usize]) {
             (i >> 9) & 1,
             (i >> 10) & 1,
             (i >> 11) & 1,
-        );
+        )?;
+    }
+    Ok(())
+}
+
+// When a worker fails because the writer thread has stopped, the writer's error is the one to report.
+fn output_failure(output: &mut Output, err: std::io::Error) -> std::io::Error {
+    match output.finish() {
+        Err(output_err) if output_err.kind() == std::io::ErrorKind::BrokenPipe => {
+            eprintln!("output was closed before all read pairs were written");
+            output_err
+        }
+        Err(output_err) if err.kind() == std::io::ErrorKind::Other => output_err,
+        _ => err,
     }
 }
 
 fn doit2_inner_inner<Src>(
     query: Src,
     opt_prog: Option<ProgressBar>,
-    writers: LocalBlockPairWriter,
+    formatter: Box<dyn PairFormatter + Send>,
+    pairs: &PairOptions,
 ) -> std::io::Result<Remainder>
 where
     Src: Iterator<Item = std::io::Result<Record>>,
 {
-    let pairer = Pairer::new(query, opt_prog);
+    let mut pairer = Pairer::new(query, opt_prog);
+    if let Some(sorter) = &pairs.sorter {
+        let mut runs = sorter.writer();
+        for pair in &mut pairer {
+            runs.write(pair?)?;
+        }
+        runs.flush()?;
+        return Ok(pairer.remainder());
+    }
     let mut shuffler = Shuffler::new(65536, 19, pairer);
-    let mut formatter = ReadParFormatter::new(writers);
-    while let Some(res_pair) = shuffler.next() {
-        let pair = res_pair?;
-        formatter.write(pair)?;
+    let filtered = process_pairs(&mut shuffler, formatter, pairs)?;
+    let mut remainder = shuffler.src.remainder();
+    remainder.filtered = filtered;
+    Ok(remainder)
+}
+
+// Everything that happens to a pair between pairing and formatting.
+fn process_pairs<Src>(
+    src: Src,
+    mut formatter: Box<dyn PairFormatter + Send>,
+    pairs: &PairOptions,
+) -> std::io::Result<FilterCounts>
+where
+    Src: Iterator<Item = std::io::Result<(Record, Record)>>,
+{
+    let mut filtered = FilterCounts::default();
+    let mut detector = if pairs.detect_duplicates {
+        Some(DuplicateDetector::new())
+    } else {
+        None
+    };
+    let mut orphans = pairs
+        .orphans
+        .as_ref()
+        .map(|orphans| orphans.writer(&pairs.format));
+    for res_pair in src {
+        let mut pair = res_pair?;
+        // Taken before duplicate marking, clipping and trimming change the flags, CIGARs and
+        // orientation.
+        let origin = pairs
+            .origins
+            .as_ref()
+            .map(|header| origin_line(header, &pair));
+        let mut reasons = pairs.filter.reasons(&pair);
+        // Only pairs that are kept get to claim a position.
+        if let Some(detector) = &mut detector {
+            if reasons.is_empty() && detector.check(&pair) {
+                mark_duplicate(&mut pair);
+            }
+        }
+        if reasons.is_empty() && is_duplicate(&pair) {
+            filtered.duplicates += 1;
+            match pairs.duplicates {
+                DuplicatePolicy::Keep => {}
+                DuplicatePolicy::Drop => reasons.push("--duplicates".to_string()),
+                DuplicatePolicy::Annotate => mark_duplicate(&mut pair),
+            }
+        }
+        if !reasons.is_empty() {
+            filtered.add(reasons);
+            if let Some(orphans) = &mut orphans {
+                orphans.write_pair(pair)?;
+            }
+            continue;
+        }
+        if pairs.soft_clips != SoftClips::Keep {
+            clip_soft_clips(&mut pair.0, pairs.soft_clips, &mut filtered.trimmed);
+            clip_soft_clips(&mut pair.1, pairs.soft_clips, &mut filtered.trimmed);
+        }
+        if let Some(trimmer) = &pairs.trimmer {
+            let (mut r1, mut r2) = orient_pair(pair);
+            let long_enough = trimmer.trim(&mut r1, &mut r2, &mut filtered.trimmed);
+            if long_enough != (true, true) {
+                filtered.add(vec!["--min-length".to_string()]);
+                if let Some(orphans) = &mut orphans {
+                    for (rec, long_enough) in [(r1, long_enough.0), (r2, long_enough.1)] {
+                        if long_enough {
+                            orphans.write(rec)?;
+                        }
+                    }
+                }
+                continue;
+            }
+            pair = (r1, r2);
+        }
+        if let Some(bins) = &pairs.format.quality_bins {
+            for rec in [&pair.0, &pair.1] {
+                let qual = rec.quality_scores().as_ref();
+                filtered.binned.0 += bins.changed(qual);
+                filtered.binned.1 += qual.len();
+            }
+        }
+        match &origin {
+            Some(origin) => formatter.write_with_origin(pair, origin)?,
+            None => formatter.write(pair)?,
+        }
     }
     formatter.flush()?;
-
-    Ok(shuffler.src.remainder())
+    if let Some(orphans) = &mut orphans {
+        orphans.flush()?;
+    }
+    Ok(filtered)
 }
 
 fn doit2_inner(
     bam: &str,
     chrom_name: &str,
     opt_prog: Option<ProgressBar>,
-    writers: LocalBlockPairWriter,
+    formatter: Box<dyn PairFormatter + Send>,
+    pairs: &PairOptions,
 ) -> std::io::Result<Remainder> {
     let mut reader = bam::indexed_reader::Builder::default().build_from_path(bam)?;
     let hdr = reader.read_header()?;
 
     if chrom_name == "*" {
         let unmapped = reader.query_unmapped(&hdr)?;
-        return doit2_inner_inner(unmapped, opt_prog, writers);
+        return doit2_inner_inner(unmapped, opt_prog, formatter, pairs);
     }
 
     let query: bam::reader::Query<std::fs::File> =
         reader.query(&hdr, &Region::new(chrom_name, ..))?;
-    doit2_inner_inner(query, opt_prog, writers)
+    doit2_inner_inner(query, opt_prog, formatter, pairs)
+}
+
+#[derive(Default)]
+pub struct ReportOptions {
+    pub write_unpaired_reads: bool,
+    pub manifest: Option<String>,
+    pub flagstat: Option<String>,
+    pub flagstat_json: Option<String>,
+    pub histograms: bool,
+    pub header_sidecar: Option<String>,
+    pub rg_args: Option<String>,
+}
+
+// What happens to pairs between the pairer and the output.
+#[derive(Clone, Default)]
+pub struct PairOptions {
+    pub filter: PairFilter,
+    pub duplicates: DuplicatePolicy,
+    pub detect_duplicates: bool,
+    pub format: FormatOptions,
+    pub soft_clips: SoftClips,
+    pub trimmer: Option<Trimmer>,
+    pub orphans: Option<OrphanWriter>,
+    pub sorter: Option<NameSorter>,
+    // The header to name references from in the sidecar, when one is written.
+    pub origins: Option<Arc<sam::Header>>,
+}
+
+impl PairOptions {
+    fn reports_filtering(&self) -> bool {
+        self.filter.is_active()
+            || self.duplicates != DuplicatePolicy::Keep
+            || self.detect_duplicates
+            || self.soft_clips == SoftClips::Remove
+            || self.trimmer.is_some()
+            || self.format.quality_bins.is_some()
+    }
+}
+
+fn optional(txt: &str) -> Option<String> {
+    if txt.is_empty() {
+        None
+    } else {
+        Some(txt.to_string())
+    }
 }
 
 pub fn doit2(
-    bam: &str,
-    filename_1: &str,
-    filename_2: &str,
+    bam: &BamInput,
+    mut output: Output,
     verbose: bool,
     num_threads: usize,
-    compression: Option<Compression>,
-    write_unpaired_reads: bool,
+    reports: &ReportOptions,
+    pairs: &PairOptions,
 ) -> std::io::Result<()> {
     let target = ProgressDrawTarget::stderr_with_hz(1);
     let multi = MultiProgress::with_draw_target(target);
@@ -223,11 +604,15 @@ pub fn doit2(
     )
     .unwrap();
 
-    let writers: BlockPairWriter = BlockPairWriter::new((filename_1, filename_2), compression)?;
-
     let pool = ThreadPool::new(num_threads);
 
-    let chrom_info = gather_chromosome_info(bam)?;
+    let chrom_info = &bam.info;
+    if let Some(filename) = &reports.header_sidecar {
+        write_provenance(filename, &chrom_info.3)?;
+    }
+    if let Some(filename) = &reports.rg_args {
+        write_read_group_args(filename, &chrom_info.3)?;
+    }
 
     let total_record_count = sum(&chrom_info.2);
     let opt_glob_prog = if verbose {
@@ -265,61 +650,334 @@ pub fn doit2(
         } else {
             None
         };
-        let bam_name = bam.to_string();
-        let writers: LocalBlockPairWriter = writers.writers(&chrom_name)?;
+        let bam_name = bam.filename.clone();
+        let formatter = output.formatter(&chrom_name, &pairs.format)?;
+        let pairs = pairs.clone();
         pool.execute(move || {
-            let remainder =
-                doit2_inner(&bam_name, &chrom_name, opt_prog, writers).expect("doit2_inner failed");
-            tx.send((chrom_num, remainder)).expect("send failed");
+            let res = doit2_inner(&bam_name, &chrom_name, opt_prog, formatter, &pairs);
+            tx.send((chrom_num, res)).expect("send failed");
         });
     }
     opt_tx.take();
 
     let mut flags = Vec::new();
     flags.resize(1 << 16, 0);
+    let mut mate_other_chr: MateOtherChr = [(0, 0); 2];
+    let mut histograms = Histograms::new();
+    let mut chrom_histograms = Vec::new();
+    let mut filtered = FilterCounts::default();
 
     let mut remainder_stats = Summariser::new();
 
     let mut unpaired = vec![];
-    for (chrom_num, remainder) in rx {
+    let mut failure = None;
+    for (chrom_num, res) in rx {
+        todo -= 1;
+        let mut remainder = match res {
+            Ok(remainder) => remainder,
+            Err(err) => {
+                failure.get_or_insert(err);
+                continue;
+            }
+        };
         for i in 0..remainder.flags.len() {
             flags[i] += remainder.flags[i];
         }
+        for (total, part) in mate_other_chr.iter_mut().zip(remainder.mate_other_chr) {
+            total.0 += part.0;
+            total.1 += part.1;
+        }
+        histograms.add_other(&remainder.histograms);
+        if reports.histograms {
+            chrom_histograms.push((chrom_num, std::mem::take(&mut remainder.histograms)));
+        }
+        filtered.add_other(&remainder.filtered);
         remainder_stats.add(remainder.tail.len() as f64);
 
         unpaired.push(remainder);
         if let Some(glob_prog) = &opt_glob_prog {
             glob_prog.inc(chrom_info.2[chrom_num] as u64);
         }
-        todo -= 1;
     }
     assert_eq!(todo, 0);
     pool.join();
+    if let Some(err) = failure {
+        return Err(output_failure(&mut output, err));
+    }
 
     let unpaired_iterator = unpaired
         .into_iter()
         .flat_map(|x| x.tail.into_values())
         .map(make_ok);
-    let writers: LocalBlockPairWriter = writers.writers("<>")?;
-    let final_remainder = doit2_inner_inner(unpaired_iterator, None, writers)?;
+    let formatter = output.formatter("<>", &pairs.format)?;
+    let final_remainder = match doit2_inner_inner(unpaired_iterator, None, formatter, pairs) {
+        Ok(remainder) => remainder,
+        Err(err) => return Err(output_failure(&mut output, err)),
+    };
+    filtered.add_other(&final_remainder.filtered);
+    if let Some(sorter) = &pairs.sorter {
+        let formatter = output.formatter("sorted", &pairs.format)?;
+        match sorter
+            .merge()
+            .and_then(|merged| process_pairs(merged, formatter, pairs))
+        {
+            Ok(sorted) => filtered.add_other(&sorted),
+            Err(err) => return Err(output_failure(&mut output, err)),
+        }
+    }
+    if let Some(orphans) = &pairs.orphans {
+        let mut writer = orphans.writer(&pairs.format);
+        for rec in final_remainder.tail.values() {
+            writer.write(rec.clone())?;
+        }
+        writer.flush()?;
+        orphans.finish()?;
+    }
+    let output_counts = output.finish().inspect_err(|err| {
+        if err.kind() == std::io::ErrorKind::BrokenPipe {
+            eprintln!("output was closed before all read pairs were written");
+        }
+    })?;
+
+    // Keep stdout clean for the reads when they are being written there.
+    let mut report: Box<dyn Write> = if output.uses_stdout() {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    };
+
+    print_flags(&mut report, "", &flags)?;
 
-    print_flags("", &flags);
+    if reports.histograms {
+        chrom_histograms.sort_by_key(|(chrom_num, _)| *chrom_num);
+        for (chrom_num, chrom_histograms) in chrom_histograms.iter() {
+            chrom_histograms.write(&mut report, &chrom_info.0[*chrom_num])?;
+        }
+        histograms.write(&mut report, "all")?;
+    }
 
-    println!("unpaired: {}", final_remainder.tail.len());
-    if write_unpaired_reads {
+    if output.is_split() {
+        let mut i = 0;
+        while i < output_counts.len() {
+            let key = &output_counts[i].key;
+            let mut count = 0;
+            while i < output_counts.len() && output_counts[i].key == *key {
+                count += output_counts[i].count;
+                i += 1;
+            }
+            writeln!(report, "pairs: {}\t{}", key, count)?;
+        }
+    }
+    if let Some(manifest) = &reports.manifest {
+        write_manifest(manifest, &output_counts)?;
+    }
+    let flagstat = FlagStat::new(&flags, &mate_other_chr);
+    if let Some(filename) = &reports.flagstat {
+        let mut out = open_writer(filename)?;
+        flagstat.write_text(&mut out)?;
+        out.flush()?;
+    }
+    if let Some(filename) = &reports.flagstat_json {
+        let mut out = open_writer(filename)?;
+        flagstat.write_json(&mut out)?;
+        out.flush()?;
+    }
+
+    if pairs.reports_filtering() {
+        filtered.write(&mut report)?;
+    }
+    writeln!(report, "unpaired: {}", final_remainder.tail.len())?;
+    write_summary(&mut report, "remainders", &remainder_stats)?;
+    if reports.write_unpaired_reads {
         if true {
             let mut unpaired_flags = Vec::new();
             unpaired_flags.resize(1 << 16, 0);
             for e in final_remainder.tail.iter() {
                 unpaired_flags[e.1.flags().bits() as usize] += 1;
             }
-            print_flags("unpaired_", &unpaired_flags);
+            print_flags(&mut report, "unpaired_", &unpaired_flags)?;
         }
         for e in final_remainder.tail.iter() {
-            println!("read_id: {}", e.0);
+            writeln!(report, "read_id: {}", e.0)?;
         }
     }
-    Ok(())
+    report.flush()
+}
+
+pub fn bam_digest(
+    bam: &BamInput,
+    mode: DigestMode,
+    sketch_size: usize,
+    verbose: bool,
+    num_threads: usize,
+    reports: &ReportOptions,
+    pairs: &PairOptions,
+) -> std::io::Result<PairDigest> {
+    let total = Arc::new(Mutex::new(PairDigest::with_sketch_size(sketch_size)));
+    let output = Output::Digest(total.clone(), mode);
+    doit2(bam, output, verbose, num_threads, reports, pairs)?;
+    let digest = std::mem::take(&mut *total.lock().unwrap());
+    Ok(digest)
+}
+
+pub fn verify(bam_digest: &PairDigest, fastq_checksum: &Checksum) -> std::io::Result<()> {
+    let fastq_digest = &fastq_checksum.digest;
+    let mut out = std::io::stdout();
+    writeln!(out, "bam: {}\t{}", bam_digest.count, bam_digest.hex())?;
+    writeln!(out, "fastq: {}\t{}", fastq_digest.count, fastq_digest.hex())?;
+    fastq_checksum.write_problems(&mut out)?;
+    if bam_digest.matches(fastq_digest) && fastq_checksum.is_clean() {
+        writeln!(out, "verify: OK")?;
+        return out.flush();
+    }
+    writeln!(out, "verify: MISMATCH")?;
+    if bam_digest.count != fastq_digest.count {
+        writeln!(
+            out,
+            "pair counts differ: {} in the BAM, {} in the FASTQ files",
+            bam_digest.count, fastq_digest.count
+        )?;
+    }
+    let sample = 20;
+    let only_bam = bam_digest.sketch_only_in(fastq_digest);
+    let only_fastq = fastq_digest.sketch_only_in(bam_digest);
+    writeln!(out, "sketched pairs only in the BAM: {}", only_bam.len())?;
+    for name in only_bam.iter().take(sample) {
+        writeln!(out, "only_bam: {}", name)?;
+    }
+    writeln!(
+        out,
+        "sketched pairs only in the FASTQ files: {}",
+        only_fastq.len()
+    )?;
+    for name in only_fastq.iter().take(sample) {
+        writeln!(out, "only_fastq: {}", name)?;
+    }
+    out.flush()?;
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "BAM and FASTQ digests differ",
+    ))
+}
+
+pub fn compare(lhs_checksum: &Checksum, rhs_checksum: &Checksum) -> std::io::Result<()> {
+    let lhs = &lhs_checksum.digest;
+    let rhs = &rhs_checksum.digest;
+    let mut out = std::io::stdout();
+    writeln!(out, "first: {}\t{}", lhs.count, lhs.hex())?;
+    lhs_checksum.write_problems(&mut out)?;
+    writeln!(out, "second: {}\t{}", rhs.count, rhs.hex())?;
+    rhs_checksum.write_problems(&mut out)?;
+    writeln!(out, "pair counts equal: {}", lhs.count == rhs.count)?;
+    writeln!(out, "digests equal: {}", lhs.digest == rhs.digest)?;
+    if lhs.matches(rhs) && lhs_checksum.is_clean() && rhs_checksum.is_clean() {
+        writeln!(out, "compare: SAME")?;
+        return out.flush();
+    }
+    writeln!(out, "compare: DIFFERENT")?;
+    writeln!(out, "estimated jaccard: {:.4}", lhs.jaccard(rhs))?;
+    out.flush()?;
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "FASTQ digests differ",
+    ))
+}
+
+pub fn compare_sketches(filenames: &[&str]) -> std::io::Result<()> {
+    let mut sketches = Vec::new();
+    for filename in filenames.iter() {
+        sketches.push(Sketch::load(filename)?);
+    }
+    let mut out = std::io::stdout();
+    writeln!(
+        out,
+        "sketch1\tsketch2\tpairs1\tpairs2\tsame_digest\tjaccard\tcontainment1\tcontainment2"
+    )?;
+    for i in 0..sketches.len() {
+        for j in (i + 1)..sketches.len() {
+            let (a, b) = (&sketches[i], &sketches[j]);
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}",
+                filenames[i],
+                filenames[j],
+                a.count,
+                b.count,
+                a.count == b.count && a.digest == b.digest,
+                a.jaccard(b),
+                a.containment(b),
+                b.containment(a)
+            )?;
+        }
+    }
+    out.flush()
+}
+
+pub fn make_filter(
+    args: &docopt::ArgvMap,
+    header: Option<&sam::Header>,
+) -> std::io::Result<PairFilter> {
+    let mut filter = PairFilter {
+        required: parse_flags(args.get_str("-f"))?,
+        excluded: parse_flags(args.get_str("-F"))?,
+        ..PairFilter::default()
+    };
+    if let Some(mates) = optional(args.get_str("--mapped")) {
+        filter.mapped = Some(Mates::parse(&mates)?);
+    }
+    if let Some(min_mapq) = optional(args.get_str("--min-mapq")) {
+        let min_mapq = min_mapq
+            .parse::<u8>()
+            .expect("--min-mapq must be an integer");
+        filter.min_mapq = Some((min_mapq, Mates::parse(args.get_str("--mapq-mates"))?));
+    }
+    let include = optional(args.get_str("--include-contigs"));
+    let exclude = optional(args.get_str("--exclude-contigs"));
+    if include.is_some() || exclude.is_some() {
+        let header = header.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "--include-contigs and --exclude-contigs need a BAM",
+            )
+        })?;
+        if let Some(names) = include {
+            filter.include_contigs = Some(contig_ids(header, &parse_names(&names)?)?);
+        }
+        if let Some(names) = exclude {
+            filter.exclude_contigs = contig_ids(header, &parse_names(&names)?)?;
+        }
+    }
+    Ok(filter)
+}
+
+pub fn make_trimmer(args: &docopt::ArgvMap) -> std::io::Result<Option<Trimmer>> {
+    let mut trimmer = Trimmer {
+        overlap: args.get_bool("--trim-adapters"),
+        trim_ns: args.get_bool("--trim-ns"),
+        min_length: args
+            .get_str("--min-length")
+            .parse::<usize>()
+            .expect("--min-length must be an integer"),
+        ..Trimmer::default()
+    };
+    if trimmer.overlap {
+        trimmer.adapters = parse_adapters(args.get_str("--adapters"))?;
+    }
+    if let Some(min_quality) = optional(args.get_str("--trim-quality")) {
+        trimmer.min_quality = Some(
+            min_quality
+                .parse::<u8>()
+                .expect("--trim-quality must be an integer"),
+        );
+    }
+    if !trimmer.overlap
+        && !trimmer.trim_ns
+        && trimmer.min_quality.is_none()
+        && trimmer.min_length == 0
+    {
+        return Ok(None);
+    }
+    Ok(Some(trimmer))
 }
 
 fn main() -> std::io::Result<()> {
@@ -330,16 +988,27 @@ fn main() -> std::io::Result<()> {
 
     let verbose = args.get_bool("-v");
 
-    if args.get_bool("-X") {
-        let _sum1 = compute_checksum(args.get_str("<fastq1>"), args.get_str("<fastq2>"))?;
-        return Ok(());
-    }
-
     let num_threads = args
         .get_str("-t")
         .parse::<usize>()
         .expect("-t must be an integer");
 
+    let bam = match optional(args.get_str("<bam>")) {
+        Some(filename) => Some(BamInput::open(&filename)?),
+        None => None,
+    };
+    let header = bam.as_ref().map(|bam| &bam.info.3);
+
+    let reports = ReportOptions {
+        write_unpaired_reads: args.get_bool("-U"),
+        manifest: optional(args.get_str("--manifest")),
+        flagstat: optional(args.get_str("--flagstat")),
+        flagstat_json: optional(args.get_str("--flagstat-json")),
+        histograms: args.get_bool("--histograms"),
+        header_sidecar: optional(args.get_str("--header-sidecar")),
+        rg_args: optional(args.get_str("--rg-args")),
+    };
+
     let compression = if args.get_str("-C") != "" && args.get_str("-C") != "none" {
         let res = make_compression(args.get_str("-C"))?;
         Some(res)
@@ -347,15 +1016,203 @@ fn main() -> std::io::Result<()> {
         None
     };
 
-    doit2(
-        args.get_str("<bam>"),
-        args.get_str("<fastq1>"),
-        args.get_str("<fastq2>"),
-        verbose,
-        num_threads,
-        compression,
-        args.get_bool("-U"),
-    )?;
+    let duplicates = DuplicatePolicy::parse(args.get_str("--duplicates"))?;
+    let soft_clips = SoftClips::parse(args.get_str("--soft-clips"))?;
+    if soft_clips == SoftClips::Lowercase && args.get_bool("-B") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "unaligned BAM can't hold lowercase bases",
+        ));
+    }
+    let pairs = PairOptions {
+        filter: make_filter(&args, header)?,
+        duplicates,
+        detect_duplicates: args.get_bool("--detect-duplicates"),
+        format: FormatOptions {
+            annotate_duplicates: duplicates == DuplicatePolicy::Annotate,
+            lowercase_soft_clips: soft_clips == SoftClips::Lowercase,
+            umi: UmiPlacement::parse(args.get_str("--umi"))?,
+            cell_barcode: args.get_bool("--cell-barcode"),
+            quality_bins: match optional(args.get_str("--bin-qualities")) {
+                Some(bins) => Some(QualityBins::parse(&bins)?),
+                None => None,
+            },
+        },
+        soft_clips,
+        trimmer: make_trimmer(&args)?,
+        orphans: match optional(args.get_str("--orphans")) {
+            Some(filename) => Some(OrphanWriter::new(&filename, compression)?),
+            None => None,
+        },
+        sorter: match header {
+            Some(header) if args.get_bool("--sort-by-name") => {
+                let max_pairs = make_size(args.get_str("--sort-pairs"))?;
+                let tmp_dir = optional(args.get_str("--tmp-dir"))
+                    .unwrap_or_else(|| std::env::temp_dir().to_string_lossy().to_string());
+                Some(NameSorter::new(&tmp_dir, header.clone(), max_pairs)?)
+            }
+            _ => None,
+        },
+        origins: match header {
+            Some(header) if !args.get_str("--sidecar").is_empty() => Some(Arc::new(header.clone())),
+            _ => None,
+        },
+    };
+
+    let digest_mode = DigestMode::parse(args.get_str("--digest"))?;
+    let sketch_size = args
+        .get_str("-k")
+        .parse::<usize>()
+        .expect("-k must be an integer");
+
+    if args.get_bool("-X") {
+        let checksum = match &bam {
+            None => fastq_digest(
+                args.get_str("<fastq1>"),
+                args.get_str("<fastq2>"),
+                digest_mode,
+                sketch_size,
+                num_threads,
+            )?,
+            Some(bam) => Checksum::from(bam_digest(
+                bam,
+                digest_mode,
+                sketch_size,
+                verbose,
+                num_threads,
+                &reports,
+                &pairs,
+            )?),
+        };
+        let sketch_file = optional(args.get_str("--sketch"));
+        return checksum.report(args.get_bool("--json"), sketch_file.as_deref());
+    }
+
+    if args.get_bool("compare-sketches") {
+        return compare_sketches(&args.get_vec("<sketch>"));
+    }
+
+    if args.get_bool("compare") {
+        let lhs = fastq_digest(
+            args.get_str("<fastq1>"),
+            args.get_str("<fastq2>"),
+            digest_mode,
+            sketch_size,
+            num_threads,
+        )?;
+        let rhs = fastq_digest(
+            args.get_str("<other1>"),
+            args.get_str("<other2>"),
+            digest_mode,
+            sketch_size,
+            num_threads,
+        )?;
+        return compare(&lhs, &rhs);
+    }
+
+    // Every other form of the command reads a BAM.
+    let bam = bam.expect("docopt requires <bam>");
+
+    if args.get_bool("verify") {
+        let bam_digest = bam_digest(
+            &bam,
+            digest_mode,
+            sketch_size,
+            verbose,
+            num_threads,
+            &reports,
+            &pairs,
+        )?;
+        let fastq_digest = fastq_digest(
+            args.get_str("<fastq1>"),
+            args.get_str("<fastq2>"),
+            digest_mode,
+            sketch_size,
+            num_threads,
+        )?;
+        return verify(&bam_digest, &fastq_digest);
+    }
+
+    let shards = args
+        .get_str("--shards")
+        .parse::<usize>()
+        .expect("--shards must be an integer");
+    if shards > 0 && args.get_bool("-R") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "-R and --shards cannot be used together",
+        ));
+    }
+
+    let limits = OutputLimits {
+        max_pairs: args
+            .get_str("--max-pairs")
+            .parse::<usize>()
+            .expect("--max-pairs must be an integer"),
+        max_bytes: make_size(args.get_str("--max-bytes"))?,
+    };
+
+    let sidecar = optional(args.get_str("--sidecar"));
+    if sidecar.is_some() && args.get_bool("-B") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "--sidecar is only written alongside FASTQ output",
+        ));
+    }
+    let header = &bam.info.3;
+    let output = if args.get_bool("-B") {
+        let command_line = std::env::args().collect::<Vec<String>>().join(" ");
+        let header = unaligned_header(header, &command_line);
+        let tags = parse_tags(args.get_str("-T"))?;
+        let writer = UnalignedBamWriter::new(args.get_str("<ubam>"), header, compression)?;
+        Output::UnalignedBam(writer, tags)
+    } else if args.get_bool("-R") {
+        // Untagged pairs have an output of their own, which no read group may share.
+        let mut keys: Vec<String> = header.read_groups().keys().cloned().collect();
+        keys.push(NO_READ_GROUP.to_string());
+        let writers = BlockPairWriter::with_template(
+            (args.get_str("<fastq1>"), args.get_str("<fastq2>")),
+            sidecar.as_deref(),
+            "{rg}",
+            keys,
+            false,
+            limits,
+            compression,
+        )?;
+        Output::Fastq(writers, OutputSplit::ReadGroup)
+    } else if shards > 0 {
+        let keys: Vec<String> = (0..shards).map(|i| shard_name(i, shards)).collect();
+        let writers = BlockPairWriter::with_template(
+            (args.get_str("<fastq1>"), args.get_str("<fastq2>")),
+            sidecar.as_deref(),
+            "{shard}",
+            keys,
+            true,
+            limits,
+            compression,
+        )?;
+        Output::Fastq(writers, OutputSplit::Shards(shards))
+    } else if limits.is_limited() {
+        let writers = BlockPairWriter::with_template(
+            (args.get_str("<fastq1>"), args.get_str("<fastq2>")),
+            sidecar.as_deref(),
+            "",
+            vec![String::new()],
+            true,
+            limits,
+            compression,
+        )?;
+        Output::Fastq(writers, OutputSplit::Single)
+    } else {
+        let writers = BlockPairWriter::new(
+            (args.get_str("<fastq1>"), args.get_str("<fastq2>")),
+            sidecar.as_deref(),
+            compression,
+        )?;
+        Output::Fastq(writers, OutputSplit::Single)
+    };
+
+    doit2(&bam, output, verbose, num_threads, &reports, &pairs)?;
 
     Ok(())
 }
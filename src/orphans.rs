@@ -0,0 +1,82 @@
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use flate2::Compression;
+use noodles::sam::alignment::Record;
+
+use crate::{
+    block_writer::{encode_block, open_output},
+    formatter::{orient_read, FormatOptions},
+};
+
+// Single-end FASTQ of the reads that don't reach the pair output: those left without a mate,
+// and both mates of any pair the filters turn away.
+#[derive(Clone)]
+pub struct OrphanWriter {
+    compression: Option<Compression>,
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl OrphanWriter {
+    pub fn new(filename: &str, compression: Option<Compression>) -> std::io::Result<OrphanWriter> {
+        Ok(OrphanWriter {
+            compression,
+            out: Arc::new(Mutex::new(open_output(filename)?)),
+        })
+    }
+
+    pub fn writer(&self, options: &FormatOptions) -> LocalOrphanWriter {
+        LocalOrphanWriter {
+            compression: self.compression,
+            options: *options,
+            buffer: Vec::new(),
+            out: self.out.clone(),
+        }
+    }
+
+    pub fn finish(&self) -> std::io::Result<()> {
+        self.out.lock().unwrap().flush()
+    }
+}
+
+pub struct LocalOrphanWriter {
+    compression: Option<Compression>,
+    options: FormatOptions,
+    buffer: Vec<u8>,
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl LocalOrphanWriter {
+    pub fn write(&mut self, rec: Record) -> std::io::Result<()> {
+        let rec = orient_read(rec);
+        let read_id = self.options.read_id(&rec);
+        self.options
+            .write_fastq_record(&mut self.buffer, &read_id, &rec)?;
+        if self.buffer.len() > 16 * 1024 * 1024 {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn write_pair(&mut self, pair: (Record, Record)) -> std::io::Result<()> {
+        let (r1, r2) = if pair.0.flags().is_first_segment() {
+            pair
+        } else {
+            (pair.1, pair.0)
+        };
+        self.write(r1)?;
+        self.write(r2)
+    }
+
+    // Blocks from different workers can be interleaved, as each is compressed on its own.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let data = encode_block(&self.buffer, self.compression)?;
+        self.buffer.clear();
+        self.out.lock().unwrap().write_all(&data)
+    }
+}
@@ -3,9 +3,38 @@ use std::collections::HashMap;
 use indicatif::ProgressBar;
 use noodles::sam::alignment::Record;
 
+use crate::filter::FilterCounts;
+use crate::histogram::Histograms;
+
 pub struct Remainder {
     pub tail: HashMap<String, Record>,
-    pub flags: Vec<usize>
+    pub flags: Vec<usize>,
+    pub mate_other_chr: MateOtherChr,
+    pub histograms: Histograms,
+    // Filled in by whoever filters the pairs.
+    pub filtered: FilterCounts
+}
+
+// Primary reads whose mate is mapped to a different reference, indexed by QC-fail, as
+// (all, mapping quality >= 5). This is the one flagstat category that the flags can't give.
+pub type MateOtherChr = [(usize, usize); 2];
+
+pub fn count_mate_other_chr(counts: &mut MateOtherChr, rec: &Record) {
+    let flags = rec.flags();
+    if flags.is_secondary()
+        || flags.is_supplementary()
+        || !flags.is_segmented()
+        || flags.is_unmapped()
+        || flags.is_mate_unmapped()
+        || rec.mate_reference_sequence_id() == rec.reference_sequence_id()
+    {
+        return;
+    }
+    let entry = &mut counts[flags.is_qc_fail() as usize];
+    entry.0 += 1;
+    if rec.mapping_quality().map(|q| q.get() >= 5).unwrap_or(true) {
+        entry.1 += 1;
+    }
 }
 
 pub struct Pairer<Src>
@@ -15,6 +44,8 @@ where
     src: Src,
     cache: HashMap<String, Record>,
     flags: Vec<usize>,
+    mate_other_chr: MateOtherChr,
+    histograms: Histograms,
     opt_prog: Option<ProgressBar>
 }
 
@@ -30,6 +61,8 @@ where
             src,
             cache: HashMap::new(),
             flags,
+            mate_other_chr: [(0, 0); 2],
+            histograms: Histograms::new(),
             opt_prog
         }
     }
@@ -40,7 +73,9 @@ where
         std::mem::swap(&mut self.cache, &mut tail);
         let mut flags = Vec::new();
         std::mem::swap(&mut self.flags, &mut flags);
-        Remainder { tail, flags }
+        let mate_other_chr = std::mem::take(&mut self.mate_other_chr);
+        let histograms = std::mem::take(&mut self.histograms);
+        Remainder { tail, flags, mate_other_chr, histograms, filtered: FilterCounts::default() }
     }
 }
 
@@ -58,12 +93,14 @@ where
             match rec_res {
                 Ok(rec) => {
                     self.flags[rec.flags().bits() as usize] += 1;
+                    count_mate_other_chr(&mut self.mate_other_chr, &rec);
                     if rec.flags().is_supplementary()
                         || rec.flags().is_secondary()
                         || !rec.flags().is_segmented()
                     {
                         continue;
                     }
+                    self.histograms.add(&rec);
                     match rec.read_name() {
                         None => {
                             continue;
@@ -0,0 +1,252 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+};
+
+const MAGIC: &[u8; 4] = b"MZSK";
+const VERSION: u8 = 2;
+
+// A saved bottom-k sketch: the k smallest pair hashes, cut down to their leading 64 bits,
+// along with the pair count and digest of the whole sample.
+pub struct Sketch {
+    pub k: usize,
+    pub count: usize,
+    pub digest: Vec<u8>,
+    pub hashes: Vec<u64>,
+    // Whether k hashes were kept before duplicates were dropped, in which case the sketch
+    // doesn't hold every pair even if fewer than k distinct hashes are left.
+    pub full: bool,
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl Sketch {
+    pub fn new(k: usize, count: usize, digest: Vec<u8>, hashes: Vec<u64>) -> Sketch {
+        let full = hashes.len() >= k;
+        Sketch::with_fill(k, count, digest, hashes, full)
+    }
+
+    fn with_fill(
+        k: usize,
+        count: usize,
+        digest: Vec<u8>,
+        mut hashes: Vec<u64>,
+        full: bool,
+    ) -> Sketch {
+        hashes.sort_unstable();
+        hashes.dedup();
+        Sketch {
+            k,
+            count,
+            digest,
+            hashes,
+            full,
+        }
+    }
+
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(filename)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION, self.digest.len() as u8, self.full as u8])?;
+        out.write_all(&(self.k as u32).to_le_bytes())?;
+        out.write_all(&(self.count as u64).to_le_bytes())?;
+        out.write_all(&self.digest)?;
+        out.write_all(&(self.hashes.len() as u32).to_le_bytes())?;
+        for hash in self.hashes.iter() {
+            out.write_all(&hash.to_le_bytes())?;
+        }
+        out.flush()
+    }
+
+    pub fn load(filename: &str) -> std::io::Result<Sketch> {
+        let mut src = BufReader::new(File::open(filename)?);
+        let mut magic = [0; 4];
+        src.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid(&format!("{} is not a sketch file", filename)));
+        }
+        let mut version = [0; 2];
+        src.read_exact(&mut version)?;
+        if version[0] != 1 && version[0] != VERSION {
+            return Err(invalid(&format!(
+                "{} has unsupported sketch version {}",
+                filename, version[0]
+            )));
+        }
+        // Version 1 didn't record whether the sketch was full.
+        let mut full = [0; 1];
+        if version[0] > 1 {
+            src.read_exact(&mut full)?;
+        }
+        let mut word = [0; 4];
+        let mut long = [0; 8];
+        src.read_exact(&mut word)?;
+        let k = u32::from_le_bytes(word) as usize;
+        src.read_exact(&mut long)?;
+        let count = u64::from_le_bytes(long) as usize;
+        let mut digest = vec![0; version[1] as usize];
+        src.read_exact(&mut digest)?;
+        src.read_exact(&mut word)?;
+        let n = u32::from_le_bytes(word) as usize;
+        let mut hashes = Vec::with_capacity(n);
+        for _ in 0..n {
+            src.read_exact(&mut long)?;
+            hashes.push(u64::from_le_bytes(long));
+        }
+        let full = if version[0] > 1 {
+            full[0] != 0
+        } else {
+            hashes.len() >= k
+        };
+        Ok(Sketch::with_fill(k, count, digest, hashes, full))
+    }
+
+    // A sketch that is not full holds every pair, otherwise it only speaks for hashes up to its largest.
+    fn limit(&self) -> Option<u64> {
+        if !self.full {
+            None
+        } else {
+            self.hashes.last().cloned()
+        }
+    }
+
+    // Bottom-k estimate: the share of the k smallest hashes of the union that both sides hold.
+    pub fn jaccard(&self, other: &Sketch) -> f64 {
+        let k = self.k.min(other.k);
+        let mut union = 0;
+        let mut both = 0;
+        let (mut i, mut j) = (0, 0);
+        while union < k {
+            match (self.hashes.get(i), other.hashes.get(j)) {
+                (None, None) => break,
+                (Some(a), Some(b)) if a == b => {
+                    both += 1;
+                    i += 1;
+                    j += 1;
+                }
+                (Some(a), Some(b)) if a < b => i += 1,
+                (Some(_), None) => i += 1,
+                _ => j += 1,
+            }
+            union += 1;
+        }
+        if union == 0 {
+            return 1.0;
+        }
+        both as f64 / union as f64
+    }
+
+    // The largest hash that both sketches speak for.
+    pub fn common_limit(&self, other: &Sketch) -> u64 {
+        match (self.limit(), other.limit()) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => a.or(b).unwrap_or(u64::MAX),
+        }
+    }
+
+    // Estimated share of this sample's pairs that also occur in the other, over the hash range both sketches cover.
+    pub fn containment(&self, other: &Sketch) -> f64 {
+        let limit = self.common_limit(other);
+        let mut total = 0;
+        let mut found = 0;
+        for hash in self.hashes.iter().take_while(|h| **h <= limit) {
+            total += 1;
+            if other.hashes.binary_search(hash).is_ok() {
+                found += 1;
+            }
+        }
+        if total == 0 {
+            return if other.hashes.is_empty() { 1.0 } else { 0.0 };
+        }
+        found as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("mazab-sketch-{}-{}", name, std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn sketch(k: usize, hashes: &[u64]) -> Sketch {
+        Sketch::new(k, hashes.len(), vec![1, 2, 3], hashes.to_vec())
+    }
+
+    #[test]
+    fn save_and_load() {
+        let filename = temp_file("round-trip");
+        for original in [
+            sketch(4, &[9, 3, 7, 1, 5]),
+            sketch(10, &[2, 4]),
+            sketch(3, &[6, 6, 8]),
+        ] {
+            original.save(&filename).unwrap();
+            let loaded = Sketch::load(&filename).unwrap();
+            assert_eq!(loaded.k, original.k);
+            assert_eq!(loaded.count, original.count);
+            assert_eq!(loaded.digest, original.digest);
+            assert_eq!(loaded.hashes, original.hashes);
+            assert_eq!(loaded.full, original.full);
+        }
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn bad_files() {
+        let filename = temp_file("bad");
+        sketch(4, &[1, 2, 3, 4]).save(&filename).unwrap();
+        let data = std::fs::read(&filename).unwrap();
+        for len in [0, 3, 6, 10, data.len() - 1] {
+            std::fs::write(&filename, &data[..len]).unwrap();
+            let err = Sketch::load(&filename).err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        }
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        std::fs::write(&filename, &bad_magic).unwrap();
+        let err = Sketch::load(&filename).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let mut bad_version = data;
+        bad_version[4] = 99;
+        std::fs::write(&filename, &bad_version).unwrap();
+        let err = Sketch::load(&filename).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn duplicate_hashes_still_fill_a_sketch() {
+        let s = sketch(3, &[5, 5, 9]);
+        assert_eq!(s.hashes, vec![5, 9]);
+        assert!(s.full);
+        assert_eq!(s.common_limit(&sketch(10, &[1, 2, 20])), 9);
+        assert_eq!(sketch(3, &[5, 9]).common_limit(&sketch(10, &[1])), u64::MAX);
+    }
+
+    #[test]
+    fn sketches_of_different_sizes() {
+        // Every hash from 1 to 20 on one side, the even ones on the other.
+        let all: Vec<u64> = (1..=20).collect();
+        let even: Vec<u64> = (1..=20).filter(|h| h % 2 == 0).collect();
+        let big = sketch(8, &all[..8]);
+        let small = sketch(4, &even[..4]);
+        // The 4 smallest of the union are 1..4, of which 2 and 4 are in both.
+        assert_eq!(big.jaccard(&small), 0.5);
+        assert_eq!(small.jaccard(&big), 0.5);
+        // Both sketches cover hashes up to 8.
+        assert_eq!(big.common_limit(&small), 8);
+        assert_eq!(big.containment(&small), 0.5);
+        assert_eq!(small.containment(&big), 1.0);
+
+        let exact = sketch(100, &even);
+        assert_eq!(exact.containment(&sketch(100, &all)), 1.0);
+        assert_eq!(sketch(100, &all).containment(&exact), 0.5);
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use noodles::{
+    bam, bgzf,
+    sam::{self, alignment::Record},
+};
+
+// No more runs than this are read at once, to stay well inside the open file limit.
+const MAX_MERGE_WIDTH: usize = 256;
+
+fn name_of(pair: &(Record, Record)) -> Vec<u8> {
+    match pair.0.read_name() {
+        Some(name) => {
+            let name: &[u8] = name.as_ref();
+            name.to_vec()
+        }
+        None => Vec::new(),
+    }
+}
+
+// Removed, with whatever runs are left in it, once the last sorter using it is gone.
+struct SortDir(PathBuf);
+
+impl Drop for SortDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// An external merge sort of pairs by read name, in byte order. Each worker sorts what it
+// holds into runs of BAM records, mates together, in a private temporary directory, and
+// the runs are merged at the end. The pairs are as the pairer produced them, so that the
+// records always round trip through BAM, and everything else happens to them after the merge.
+#[derive(Clone)]
+pub struct NameSorter {
+    dir: Arc<SortDir>,
+    header: Arc<sam::Header>,
+    max_pairs: usize,
+    next_run: Arc<AtomicUsize>,
+    runs: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl NameSorter {
+    pub fn new(
+        tmp_dir: &str,
+        header: sam::Header,
+        max_pairs: usize,
+    ) -> std::io::Result<NameSorter> {
+        let dir = PathBuf::from(tmp_dir).join(format!("mazab-sort-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        Ok(NameSorter {
+            dir: Arc::new(SortDir(dir)),
+            header: Arc::new(header),
+            max_pairs: max_pairs.max(1),
+            next_run: Arc::new(AtomicUsize::new(0)),
+            runs: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    pub fn writer(&self) -> RunWriter {
+        RunWriter {
+            sorter: self.clone(),
+            buffer: Vec::new(),
+        }
+    }
+
+    fn write_run<I>(&self, pairs: I) -> std::io::Result<PathBuf>
+    where
+        I: Iterator<Item = std::io::Result<(Record, Record)>>,
+    {
+        let n = self.next_run.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.0.join(format!("run-{}.bam", n));
+        let inner = bgzf::writer::Builder::default()
+            .set_compression_level(bgzf::writer::CompressionLevel::fast())
+            .build_with_writer(BufWriter::new(File::create(&path)?));
+        let mut writer = bam::Writer::from(inner);
+        writer.write_header(&self.header)?;
+        for pair in pairs {
+            let (r1, r2) = pair?;
+            writer.write_record(&self.header, &r1)?;
+            writer.write_record(&self.header, &r2)?;
+        }
+        writer.try_finish()?;
+        Ok(path)
+    }
+
+    fn open_runs(&self, paths: &[PathBuf]) -> std::io::Result<MergedPairs> {
+        let mut runs = Vec::new();
+        for path in paths.iter() {
+            let mut reader = bam::Reader::new(BufReader::new(File::open(path)?));
+            reader.read_header()?;
+            runs.push(reader);
+        }
+        let mut merged = MergedPairs {
+            header: self.header.clone(),
+            runs,
+            heap: BinaryHeap::new(),
+            current: Vec::new(),
+        };
+        for i in 0..merged.runs.len() {
+            let pair = merged.read_pair(i)?;
+            merged.current.push(pair);
+            merged.push(i);
+        }
+        Ok(merged)
+    }
+
+    // Merges runs a batch at a time until few enough are left to read together, then
+    // returns the pairs of all of them in order. Every pair has to have been written by now.
+    pub fn merge(&self) -> std::io::Result<MergedPairs> {
+        let mut runs = std::mem::take(&mut *self.runs.lock().unwrap());
+        while runs.len() > MAX_MERGE_WIDTH {
+            let batch: Vec<PathBuf> = runs.drain(..MAX_MERGE_WIDTH).collect();
+            let merged = self.write_run(self.open_runs(&batch)?)?;
+            for path in batch.iter() {
+                std::fs::remove_file(path)?;
+            }
+            runs.push(merged);
+        }
+        self.open_runs(&runs)
+    }
+}
+
+pub struct RunWriter {
+    sorter: NameSorter,
+    buffer: Vec<(Record, Record)>,
+}
+
+impl RunWriter {
+    pub fn write(&mut self, pair: (Record, Record)) -> std::io::Result<()> {
+        self.buffer.push(pair);
+        if self.buffer.len() >= self.sorter.max_pairs {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut pairs = std::mem::take(&mut self.buffer);
+        pairs.sort_by_cached_key(name_of);
+        let path = self.sorter.write_run(pairs.into_iter().map(Ok))?;
+        self.sorter.runs.lock().unwrap().push(path);
+        Ok(())
+    }
+}
+
+// The pairs of a set of runs, smallest name first.
+pub struct MergedPairs {
+    header: Arc<sam::Header>,
+    runs: Vec<bam::Reader<bgzf::Reader<BufReader<File>>>>,
+    heap: BinaryHeap<Reverse<(Vec<u8>, usize)>>,
+    current: Vec<Option<(Record, Record)>>,
+}
+
+impl MergedPairs {
+    fn read_pair(&mut self, i: usize) -> std::io::Result<Option<(Record, Record)>> {
+        let mut r1 = Record::default();
+        if self.runs[i].read_record(&self.header, &mut r1)? == 0 {
+            return Ok(None);
+        }
+        let mut r2 = Record::default();
+        if self.runs[i].read_record(&self.header, &mut r2)? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "sort run ends part way through a pair",
+            ));
+        }
+        Ok(Some((r1, r2)))
+    }
+
+    fn push(&mut self, i: usize) {
+        if let Some(pair) = &self.current[i] {
+            self.heap.push(Reverse((name_of(pair), i)));
+        }
+    }
+}
+
+impl Iterator for MergedPairs {
+    type Item = std::io::Result<(Record, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, i)) = self.heap.pop()?;
+        let pair = self.current[i].take();
+        match self.read_pair(i) {
+            Ok(next) => {
+                self.current[i] = next;
+                self.push(i);
+            }
+            Err(err) => return Some(Err(err)),
+        }
+        pair.map(Ok)
+    }
+}
@@ -1,53 +1,199 @@
-#[derive(Clone, Copy, Debug)]
+use std::collections::BTreeMap;
+
+// Relative accuracy of the quantile estimates.
+const QUANTILE_ACCURACY: f64 = 0.01;
+
+// A log-bucketed quantile sketch (after DDSketch). Every estimate is within QUANTILE_ACCURACY
+// of a true value at that rank, and two sketches merge exactly by adding their bucket counts.
+#[derive(Clone, Debug)]
+pub struct QuantileSketch {
+    gamma: f64,
+    positive: BTreeMap<i32, usize>,
+    negative: BTreeMap<i32, usize>,
+    zero: usize,
+}
+
+impl QuantileSketch {
+    pub fn new() -> QuantileSketch {
+        QuantileSketch {
+            gamma: (1.0 + QUANTILE_ACCURACY) / (1.0 - QUANTILE_ACCURACY),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0,
+        }
+    }
+
+    fn bucket(&self, x: f64) -> i32 {
+        (x.ln() / self.gamma.ln()).ceil() as i32
+    }
+
+    fn value(&self, bucket: i32) -> f64 {
+        2.0 * self.gamma.powi(bucket) / (self.gamma + 1.0)
+    }
+
+    pub fn add_multiple(&mut self, x: f64, n: usize) {
+        if x > 0.0 {
+            *self.positive.entry(self.bucket(x)).or_default() += n;
+        } else if x < 0.0 {
+            *self.negative.entry(self.bucket(-x)).or_default() += n;
+        } else {
+            self.zero += n;
+        }
+    }
+
+    pub fn add_other(&mut self, other: &QuantileSketch) {
+        for (bucket, n) in other.positive.iter() {
+            *self.positive.entry(*bucket).or_default() += n;
+        }
+        for (bucket, n) in other.negative.iter() {
+            *self.negative.entry(*bucket).or_default() += n;
+        }
+        self.zero += other.zero;
+    }
+
+    pub fn len(&self) -> usize {
+        self.positive.values().sum::<usize>() + self.negative.values().sum::<usize>() + self.zero
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn quantile(&self, q: f64) -> f64 {
+        let n = self.len();
+        if n == 0 {
+            return f64::NAN;
+        }
+        let rank = (q.clamp(0.0, 1.0) * (n - 1) as f64).round() as usize;
+        let mut seen = 0;
+        for (bucket, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return -self.value(*bucket);
+            }
+        }
+        seen += self.zero;
+        if seen > rank {
+            return 0.0;
+        }
+        for (bucket, count) in self.positive.iter() {
+            seen += count;
+            if seen > rank {
+                return self.value(*bucket);
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl Default for QuantileSketch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Summariser {
     pub n: usize,
-    sx: f64,
-    sx2: f64,
+    mean: f64,
+    m2: f64,
+    min: f64,
+    max: f64,
+    quantiles: QuantileSketch,
 }
 
 impl Summariser {
     pub fn new() -> Summariser {
         Summariser {
             n: 0,
-            sx: 0.0,
-            sx2: 0.0,
+            mean: 0.0,
+            m2: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            quantiles: QuantileSketch::new(),
         }
     }
 
     pub fn add(&mut self, x: f64) {
-        self.n += 1;
-        self.sx += x;
-        self.sx2 += x * x;
+        self.add_multiple(x, 1);
     }
 
     pub fn add_multiple(&mut self, x: f64, n: usize) {
-        self.n += n;
-        self.sx += (n as f64) * x;
-        self.sx2 += (n as f64) * x * x;
+        if n == 0 {
+            return;
+        }
+        self.combine(n, x, 0.0);
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+        self.quantiles.add_multiple(x, n);
     }
 
     pub fn add_other(&mut self, other: &Summariser) {
-        self.n += other.n;
-        self.sx += other.sx;
-        self.sx2 += other.sx2;
+        if other.n == 0 {
+            return;
+        }
+        self.combine(other.n, other.mean, other.m2);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.quantiles.add_other(&other.quantiles);
+    }
+
+    // Chan et al.'s pairwise update, which is Welford's when the other side is a single value.
+    fn combine(&mut self, n: usize, mean: f64, m2: f64) {
+        let total = self.n + n;
+        let delta = mean - self.mean;
+        self.mean += delta * (n as f64) / (total as f64);
+        self.m2 += m2 + delta * delta * (self.n as f64) * (n as f64) / (total as f64);
+        self.n = total;
     }
 
     pub fn mean(&self) -> f64 {
-        self.sx / (self.n as f64)
+        if self.n == 0 {
+            f64::NAN
+        } else {
+            self.mean
+        }
     }
 
     pub fn var(&self) -> f64 {
-        let m = self.mean();
-        self.sx2 / (self.n as f64) - m * m
+        if self.n == 0 {
+            f64::NAN
+        } else {
+            self.m2 / (self.n as f64)
+        }
     }
 
     pub fn sd(&self) -> f64 {
+        self.var().sqrt()
+    }
+
+    pub fn min(&self) -> f64 {
         if self.n == 0 {
-            -1.0
+            f64::NAN
         } else {
-            self.var().sqrt()
+            self.min
         }
     }
+
+    pub fn max(&self) -> f64 {
+        if self.n == 0 {
+            f64::NAN
+        } else {
+            self.max
+        }
+    }
+
+    // Estimates stay within the observed range.
+    pub fn quantile(&self, q: f64) -> f64 {
+        if self.n == 0 {
+            return f64::NAN;
+        }
+        self.quantiles.quantile(q).clamp(self.min, self.max)
+    }
+
+    pub fn median(&self) -> f64 {
+        self.quantile(0.5)
+    }
 }
 
 impl Default for Summariser {
@@ -55,3 +201,102 @@ impl Default for Summariser {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Repeatable values spread over several orders of magnitude, either side of zero.
+    fn values(n: usize) -> Vec<f64> {
+        let mut x = 17u64;
+        (0..n)
+            .map(|i| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let v = ((x >> 11) as f64 / (1u64 << 53) as f64) * 10f64.powi((i % 5) as i32);
+                match i % 7 {
+                    0 => -v,
+                    1 => 0.0,
+                    _ => v,
+                }
+            })
+            .collect()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1.0)
+    }
+
+    #[test]
+    fn quantiles_are_within_the_accuracy() {
+        let mut xs = values(20000);
+        let mut sketch = QuantileSketch::new();
+        for x in xs.iter() {
+            sketch.add_multiple(*x, 1);
+        }
+        xs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        for i in 0..=100 {
+            let q = i as f64 / 100.0;
+            let exact = xs[(q * (xs.len() - 1) as f64).round() as usize];
+            let estimate = sketch.quantile(q);
+            assert!(
+                (estimate - exact).abs() <= QUANTILE_ACCURACY * exact.abs() + 1e-12,
+                "q={} exact={} estimate={}",
+                q,
+                exact,
+                estimate
+            );
+        }
+        assert!(QuantileSketch::new().quantile(0.5).is_nan());
+    }
+
+    #[test]
+    fn merged_summaries_match_a_single_pass() {
+        let xs = values(10000);
+        let mut whole = Summariser::new();
+        let mut parts = vec![Summariser::new(); 4];
+        for (i, x) in xs.iter().enumerate() {
+            whole.add(*x);
+            // Uneven shares, and some values added several at once.
+            let part = &mut parts[(i * i) % 4];
+            if i % 10 == 0 {
+                part.add_multiple(*x, 3);
+                whole.add_multiple(*x, 2);
+            } else {
+                part.add(*x);
+            }
+        }
+        let mut merged = Summariser::new();
+        merged.add_other(&Summariser::new());
+        for part in parts.iter() {
+            merged.add_other(part);
+        }
+        assert_eq!(merged.n, whole.n);
+        assert!(close(merged.mean(), whole.mean()));
+        assert!(close(merged.var(), whole.var()));
+        assert_eq!(merged.min(), whole.min());
+        assert_eq!(merged.max(), whole.max());
+        for i in 0..=20 {
+            let q = i as f64 / 20.0;
+            assert_eq!(merged.quantile(q), whole.quantile(q));
+        }
+    }
+
+    #[test]
+    fn welford_matches_the_textbook_formulas() {
+        let xs = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        let mut s = Summariser::new();
+        for x in xs.iter() {
+            s.add(*x);
+        }
+        assert!(close(s.mean(), 5.0));
+        assert!(close(s.var(), 4.0));
+        assert!(close(s.sd(), 2.0));
+        assert_eq!(s.min(), 2.0);
+        assert_eq!(s.max(), 9.0);
+        // The median rank rounds up to the fifth value.
+        assert!((s.median() - 5.0).abs() <= QUANTILE_ACCURACY * 5.0);
+        assert!(Summariser::new().mean().is_nan());
+    }
+}
@@ -0,0 +1,437 @@
+use std::{collections::BTreeMap, io::Write};
+
+use noodles::sam::{
+    alignment::Record,
+    record::{cigar::Op, sequence::Base, Sequence},
+};
+
+use crate::formatter::{complement, soft_clip_lengths};
+
+// Shortest insert that the mates' overlap is trusted to reveal.
+const MIN_INSERT_OVERLAP: usize = 20;
+
+// Shortest stretch of an adapter at the very end of a read that is trimmed.
+const MIN_ADAPTER_OVERLAP: usize = 3;
+
+pub fn parse_adapters(txt: &str) -> std::io::Result<Vec<Vec<Base>>> {
+    let mut adapters = Vec::new();
+    for adapter in txt.split(',').filter(|adapter| !adapter.is_empty()) {
+        let seq = adapter
+            .to_ascii_uppercase()
+            .parse::<Sequence>()
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("invalid adapter sequence: {}", adapter),
+                )
+            })?;
+        adapters.push(seq.as_ref().to_vec());
+    }
+    Ok(adapters)
+}
+
+// Up to one mismatch in ten, with N matching anything.
+fn matches(a: &[Base], b: &[Base]) -> bool {
+    let allowed = a.len() / 10;
+    let mut mismatches = 0;
+    for (x, y) in a.iter().zip(b) {
+        if x != y && *x != Base::N && *y != Base::N {
+            mismatches += 1;
+            if mismatches > allowed {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// When the insert is shorter than the reads, R1 starts with the reverse complement of the
+// end of R2, and everything past the insert in either read is adapter.
+fn insert_length(r1: &[Base], r2: &[Base]) -> Option<usize> {
+    let r2_rc: Vec<Base> = r2.iter().rev().map(|b| complement(*b)).collect();
+    let n = r1.len().min(r2.len());
+    (MIN_INSERT_OVERLAP..n)
+        .rev()
+        .find(|len| matches(&r1[..*len], &r2_rc[r2.len() - len..]))
+}
+
+// Where the leftmost adapter starts, whole or running off the end of the read.
+fn adapter_start(seq: &[Base], adapters: &[Vec<Base>]) -> Option<usize> {
+    (0..seq.len().saturating_sub(MIN_ADAPTER_OVERLAP - 1)).find(|i| {
+        adapters.iter().any(|adapter| {
+            let len = adapter.len().min(seq.len() - i);
+            matches(&adapter[..len], &seq[*i..*i + len])
+        })
+    })
+}
+
+// The BWA rule: cut where the sum of (threshold - quality) from the 3' end is greatest.
+fn quality_end(qual: &[u8], min_quality: u8) -> usize {
+    let mut sum = 0i64;
+    let mut best = 0i64;
+    let mut end = qual.len();
+    for (i, q) in qual.iter().enumerate().rev() {
+        sum += min_quality as i64 - *q as i64;
+        if sum < 0 {
+            break;
+        }
+        if sum > best {
+            best = sum;
+            end = i;
+        }
+    }
+    end
+}
+
+// Takes n read bases off the end of the CIGAR, along with any other ops at the cut.
+fn drop_cigar_end(ops: &mut Vec<Op>, mut n: usize) {
+    while n > 0 {
+        let Some(op) = ops.pop() else {
+            return;
+        };
+        if !op.kind().consumes_read() {
+            continue;
+        }
+        if op.len() > n {
+            ops.push(Op::new(op.kind(), op.len() - n));
+            return;
+        }
+        n -= op.len();
+    }
+}
+
+// The CIGAR is cut along with the bases, so the soft clips it gives still line up with them
+// when they are lowercased on the way out.
+fn keep(rec: &mut Record, start: usize, end: usize) {
+    let len = rec.sequence().len();
+    let end = end.min(len);
+    let ops = rec.cigar_mut().as_mut();
+    drop_cigar_end(ops, len - end);
+    ops.reverse();
+    drop_cigar_end(ops, start.min(end));
+    ops.reverse();
+
+    let seq = rec.sequence_mut().as_mut();
+    seq.truncate(end);
+    seq.drain(..start.min(end));
+    let qual = rec.quality_scores_mut().as_mut();
+    if !qual.is_empty() {
+        qual.truncate(end);
+        qual.drain(..start.min(end));
+    }
+}
+
+// What becomes of the bases an aligner soft clipped. Lowercasing happens as the reads are
+// written, everything else before they are oriented, while the CIGAR still lines up with them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SoftClips {
+    #[default]
+    Keep,
+    Remove,
+    Lowercase,
+    Mask,
+}
+
+impl SoftClips {
+    pub fn parse(txt: &str) -> std::io::Result<SoftClips> {
+        match txt {
+            "keep" => Ok(SoftClips::Keep),
+            "remove" => Ok(SoftClips::Remove),
+            "lowercase" => Ok(SoftClips::Lowercase),
+            "mask" => Ok(SoftClips::Mask),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("unknown soft clip handling: {}", txt),
+            )),
+        }
+    }
+}
+
+pub fn clip_soft_clips(rec: &mut Record, mode: SoftClips, counts: &mut TrimCounts) {
+    let (start, end) = soft_clip_lengths(rec);
+    if start + end == 0 {
+        return;
+    }
+    let len = rec.sequence().len();
+    let start = start.min(len);
+    let end = len.saturating_sub(end).max(start);
+    match mode {
+        SoftClips::Keep | SoftClips::Lowercase => {}
+        SoftClips::Remove => {
+            keep(rec, start, end);
+            counts.add("soft_clip", len, end - start);
+        }
+        SoftClips::Mask => {
+            let seq = rec.sequence_mut().as_mut();
+            for i in (0..start).chain(end..len) {
+                seq[i] = Base::N;
+            }
+        }
+    }
+}
+
+// Reads and bases trimmed for each reason.
+#[derive(Clone, Debug, Default)]
+pub struct TrimCounts {
+    pub trimmed: BTreeMap<&'static str, (usize, usize)>,
+}
+
+impl TrimCounts {
+    fn add(&mut self, kind: &'static str, before: usize, after: usize) {
+        if after < before {
+            let entry = self.trimmed.entry(kind).or_default();
+            entry.0 += 1;
+            entry.1 += before - after;
+        }
+    }
+
+    pub fn add_other(&mut self, other: &TrimCounts) {
+        for (kind, n) in other.trimmed.iter() {
+            let entry = self.trimmed.entry(kind).or_default();
+            entry.0 += n.0;
+            entry.1 += n.1;
+        }
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        for (kind, (reads, bases)) in self.trimmed.iter() {
+            writeln!(out, "trimmed: {}\t{}\t{}", kind, reads, bases)?;
+        }
+        Ok(())
+    }
+}
+
+// Trimming of pairs that have been put back into the orientation they were sequenced in.
+#[derive(Clone, Debug, Default)]
+pub struct Trimmer {
+    pub overlap: bool,
+    pub adapters: Vec<Vec<Base>>,
+    pub min_quality: Option<u8>,
+    pub trim_ns: bool,
+    pub min_length: usize,
+}
+
+impl Trimmer {
+    fn trim_read(&self, rec: &mut Record, counts: &mut TrimCounts) {
+        let len = rec.sequence().len();
+        if let Some(start) = adapter_start(rec.sequence().as_ref(), &self.adapters) {
+            keep(rec, 0, start);
+            counts.add("adapter", len, start);
+        }
+
+        if let Some(min_quality) = self.min_quality {
+            let len = rec.sequence().len();
+            let qual: Vec<u8> = rec
+                .quality_scores()
+                .as_ref()
+                .iter()
+                .map(|q| q.get())
+                .collect();
+            if !qual.is_empty() {
+                let end = quality_end(&qual, min_quality);
+                keep(rec, 0, end);
+                counts.add("quality", len, end);
+            }
+        }
+
+        if self.trim_ns {
+            let seq = rec.sequence().as_ref();
+            let end = seq
+                .iter()
+                .rposition(|b| *b != Base::N)
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            let start = seq[..end].iter().position(|b| *b != Base::N).unwrap_or(0);
+            let len = seq.len();
+            keep(rec, start, end);
+            counts.add("n", len, end - start);
+        }
+    }
+
+    // Trims both mates in place, saying which are still long enough to keep. Reads trimmed
+    // away to nothing never are.
+    pub fn trim(&self, r1: &mut Record, r2: &mut Record, counts: &mut TrimCounts) -> (bool, bool) {
+        if self.overlap {
+            let insert = insert_length(r1.sequence().as_ref(), r2.sequence().as_ref());
+            if let Some(insert) = insert {
+                for rec in [&mut *r1, &mut *r2] {
+                    let len = rec.sequence().len();
+                    keep(rec, 0, insert);
+                    counts.add("overlap", len, insert);
+                }
+            }
+        }
+        self.trim_read(r1, counts);
+        self.trim_read(r2, counts);
+        let min_length = self.min_length.max(1);
+        (
+            r1.sequence().len() >= min_length,
+            r2.sequence().len() >= min_length,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use noodles::sam::record::{Cigar, Flags, QualityScores, ReadName};
+
+    use super::*;
+    use crate::formatter::{orient_read, FormatOptions};
+
+    fn record(seq: &str, qual: &str, cigar: &str, flags: Flags) -> Record {
+        Record::builder()
+            .set_read_name("r".parse::<ReadName>().unwrap())
+            .set_flags(flags)
+            .set_sequence(seq.parse::<Sequence>().unwrap())
+            .set_quality_scores(qual.parse::<QualityScores>().unwrap())
+            .set_cigar(cigar.parse::<Cigar>().unwrap())
+            .build()
+    }
+
+    fn lowercased(rec: &Record) -> String {
+        let options = FormatOptions {
+            lowercase_soft_clips: true,
+            ..Default::default()
+        };
+        options.sequence(rec)
+    }
+
+    fn bases(txt: &str) -> Vec<Base> {
+        txt.parse::<Sequence>().unwrap().as_ref().to_vec()
+    }
+
+    // Repeatable sequence without the repeats that would let reads overlap by chance.
+    fn pseudo_random(len: usize, seed: u64) -> String {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                ['A', 'C', 'G', 'T'][(x >> 62) as usize]
+            })
+            .collect()
+    }
+
+    fn reverse_complement(txt: &str) -> String {
+        let seq: Vec<Base> = bases(txt).iter().rev().map(|b| complement(*b)).collect();
+        Sequence::from(seq).to_string()
+    }
+
+    #[test]
+    fn quality_trimming_follows_bwa() {
+        assert_eq!(quality_end(&[30, 30, 30, 10, 10, 30, 2, 2], 20), 3);
+        assert_eq!(quality_end(&[30, 30, 30, 30], 20), 4);
+        assert_eq!(quality_end(&[2, 2, 2], 20), 0);
+        // A single good base doesn't stop the cut when the run before it is bad enough.
+        assert_eq!(quality_end(&[30, 30, 5, 5, 25, 5], 20), 2);
+        assert_eq!(quality_end(&[], 20), 0);
+    }
+
+    #[test]
+    fn adapter_matching() {
+        assert!(matches(&bases("AGATCGGAAGAGC"), &bases("AGATCGGAAGAGC")));
+        assert!(matches(&bases("AGATCGGAAGAGC"), &bases("AGATCGGTAGAGC")));
+        assert!(!matches(&bases("AGATCGGAAGAGC"), &bases("AGTTCGGTAGAGC")));
+        assert!(matches(&bases("AGATCGGAA"), &bases("AGNTCGGAA")));
+        assert!(!matches(&bases("AGATCGGAA"), &bases("AGTTCGGAA")));
+    }
+
+    #[test]
+    fn adapters_are_found_whole_or_at_the_end() {
+        let adapters = parse_adapters("AGATCGGAAGAGC").unwrap();
+        let insert = pseudo_random(30, 1);
+        let read = format!("{}AGATCGGAAGAGCACACGTCT", insert);
+        assert_eq!(adapter_start(&bases(&read), &adapters), Some(30));
+        let read = format!("{}AGATC", insert);
+        assert_eq!(adapter_start(&bases(&read), &adapters), Some(30));
+        let read = format!("{}AG", insert);
+        assert_eq!(adapter_start(&bases(&read), &adapters), None);
+        assert!(parse_adapters("AGATC1G").is_err());
+    }
+
+    #[test]
+    fn insert_length_from_mate_overlap() {
+        let insert = pseudo_random(35, 2);
+        let r1 = format!("{}{}", insert, "AGATCGGAAGAGCACACGTC");
+        let r2 = format!("{}{}", reverse_complement(&insert), "AGATCGTCGGACTGTAGAAC");
+        assert_eq!(insert_length(&bases(&r1), &bases(&r2)), Some(35));
+
+        // Reads from the two ends of a longer insert don't overlap.
+        let insert = pseudo_random(120, 3);
+        let r1 = &insert[..55];
+        let r2 = reverse_complement(&insert[65..]);
+        assert_eq!(insert_length(&bases(r1), &bases(&r2)), None);
+    }
+
+    #[test]
+    fn trimming_pairs() {
+        let insert = pseudo_random(35, 4);
+        let r1 = format!("{}{}", insert, "AGATCGGAAGAGCACACGTC");
+        let r2 = format!("{}{}", reverse_complement(&insert), "AGATCGTCGGACTGTAGAAC");
+        let qual = "I".repeat(r1.len());
+        let mut r1 = record(&r1, &qual, "55M", Flags::empty());
+        let mut r2 = record(&r2, &qual, "55M", Flags::empty());
+        let trimmer = Trimmer {
+            overlap: true,
+            min_length: 36,
+            ..Default::default()
+        };
+        let mut counts = TrimCounts::default();
+        assert_eq!(trimmer.trim(&mut r1, &mut r2, &mut counts), (false, false));
+        assert_eq!(r1.sequence().to_string(), insert);
+        assert_eq!(r2.sequence().to_string(), reverse_complement(&insert));
+        assert_eq!(counts.trimmed.get("overlap"), Some(&(2, 40)));
+
+        let trimmer = Trimmer {
+            trim_ns: true,
+            ..Default::default()
+        };
+        let mut r1 = record("NNACGTNACN", "IIIIIIIIII", "10M", Flags::empty());
+        let mut r2 = record("NNNN", "IIII", "4M", Flags::empty());
+        assert_eq!(trimmer.trim(&mut r1, &mut r2, &mut counts), (true, false));
+        assert_eq!(r1.sequence().to_string(), "ACGTNAC");
+        assert_eq!(r1.cigar().to_string(), "7M");
+        assert_eq!(r2.sequence().len(), 0);
+    }
+
+    #[test]
+    fn lowercase_soft_clips_after_quality_trimming() {
+        let trimmer = Trimmer {
+            min_quality: Some(20),
+            ..Default::default()
+        };
+        let mut counts = TrimCounts::default();
+        let mut r1 = record("ACGTACGTAC", "IIIII#####", "7M3S", Flags::empty());
+        let mut r2 = r1.clone();
+        trimmer.trim(&mut r1, &mut r2, &mut counts);
+        assert_eq!(lowercased(&r1), "ACGTA");
+        assert_eq!(r1.cigar().to_string(), "5M");
+
+        let mut r1 = record("ACGTACGTAC", "IIIIIIII##", "2S5M3S", Flags::empty());
+        let mut r2 = r1.clone();
+        trimmer.trim(&mut r1, &mut r2, &mut counts);
+        assert_eq!(lowercased(&r1), "acGTACGt");
+        assert_eq!(r1.cigar().to_string(), "2S5M1S");
+    }
+
+    #[test]
+    fn lowercase_soft_clips_of_reverse_reads_after_trimming() {
+        // Sequenced as GTACGTACGT, with its first three bases clipped and its last two poor.
+        let rec = record(
+            "ACGTACGTAC",
+            "##IIIIIIII",
+            "7M3S",
+            Flags::REVERSE_COMPLEMENTED,
+        );
+        let mut r1 = orient_read(rec);
+        let mut r2 = r1.clone();
+        let trimmer = Trimmer {
+            min_quality: Some(20),
+            ..Default::default()
+        };
+        trimmer.trim(&mut r1, &mut r2, &mut TrimCounts::default());
+        assert_eq!(lowercased(&r1), "gtaCGTAC");
+        assert_eq!(r1.cigar().to_string(), "3S5M");
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    fs::File,
+    io::Write,
+    sync::mpsc::{sync_channel, SyncSender},
+    thread::JoinHandle,
+};
+
+use flate2::Compression;
+use noodles::{
+    bam, bgzf,
+    sam::{
+        self,
+        alignment::Record,
+        header::record::value::{
+            map::{
+                self,
+                header::{GroupOrder, SortOrder, Version},
+                Program,
+            },
+            Map,
+        },
+        record::{data::field::Tag, Data, Flags},
+    },
+};
+
+use crate::{
+    block_writer::DataBlock,
+    formatter::{orient_pair, FormatOptions, PairFormatter},
+};
+
+pub fn parse_tags(txt: &str) -> std::io::Result<Vec<Tag>> {
+    let mut tags = Vec::new();
+    for item in txt.split(',') {
+        if item.is_empty() {
+            continue;
+        }
+        let tag = item.parse::<Tag>().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::Other, format!("invalid tag '{}'", item))
+        })?;
+        tags.push(tag);
+    }
+    Ok(tags)
+}
+
+pub fn unaligned_header(header: &sam::Header, command_line: &str) -> sam::Header {
+    let hd = Map::<map::Header>::builder()
+        .set_version(Version::new(1, 6))
+        .set_sort_order(SortOrder::Unsorted)
+        .set_group_order(GroupOrder::Query)
+        .build()
+        .unwrap();
+
+    let mut builder = sam::Header::builder().set_header(hd);
+    for (id, read_group) in header.read_groups().iter() {
+        builder = builder.add_read_group(id, read_group.clone());
+    }
+
+    let mut last_program = None;
+    for (id, program) in header.programs().iter() {
+        builder = builder.add_program(id, program.clone());
+        last_program = Some(id.to_string());
+    }
+
+    let mut program_id = "mazab".to_string();
+    let mut n = 0;
+    while header.programs().contains_key(&program_id) {
+        n += 1;
+        program_id = format!("mazab.{}", n);
+    }
+    let mut program = Map::<Program>::builder()
+        .set_name("mazab")
+        .set_version(env!("CARGO_PKG_VERSION"))
+        .set_command_line(command_line);
+    if let Some(previous_id) = last_program {
+        program = program.set_previous_id(previous_id);
+    }
+    builder = builder.add_program(program_id, program.build().unwrap());
+
+    for comment in header.comments() {
+        builder = builder.add_comment(comment.to_string());
+    }
+
+    builder.build()
+}
+
+pub struct UnalignedBamWriter {
+    file: Option<SyncSender<DataBlock>>,
+    joiner: Option<JoinHandle<std::io::Result<()>>>,
+}
+
+impl UnalignedBamWriter {
+    pub fn new(
+        filename: &str,
+        header: sam::Header,
+        compression: Option<Compression>,
+    ) -> std::io::Result<UnalignedBamWriter> {
+        let level = if let Some(compression) = compression {
+            bgzf::writer::CompressionLevel::try_from(compression.level() as u8).unwrap_or_default()
+        } else {
+            bgzf::writer::CompressionLevel::none()
+        };
+        let file = File::create(filename)?;
+        let (tx, rx) = sync_channel::<DataBlock>(1);
+        let handle = std::thread::spawn(move || {
+            let inner = bgzf::writer::Builder::default()
+                .set_compression_level(level)
+                .build_with_writer(file);
+            let mut writer = bam::Writer::from(inner);
+            writer.write_header(&header)?;
+            for block in rx {
+                writer.get_mut().write_all(&block.data)?;
+            }
+            writer.try_finish()?;
+            Ok(())
+        });
+        Ok(UnalignedBamWriter {
+            file: Some(tx),
+            joiner: Some(handle),
+        })
+    }
+
+    pub fn writer(&self, id: &str) -> std::io::Result<LocalUnalignedBamWriter> {
+        Ok(LocalUnalignedBamWriter {
+            id: id.to_string(),
+            block_num: 0,
+            writer: self.file.clone().unwrap(),
+        })
+    }
+
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.file.take();
+        match self.joiner.take() {
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "already joined",
+            )),
+            Some(joiner) => joiner
+                .join()
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "join failed"))?,
+        }
+    }
+}
+
+pub struct LocalUnalignedBamWriter {
+    id: String,
+    block_num: usize,
+    writer: SyncSender<DataBlock>,
+}
+
+impl LocalUnalignedBamWriter {
+    pub fn write(&mut self, block: &[u8]) -> std::io::Result<()> {
+        self.block_num += 1;
+        let block_id = format!("{}:{}", self.id, self.block_num);
+        self.writer
+            .send(DataBlock {
+                id: block_id,
+                data: Vec::from(block),
+            })
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "writer thread exited"))
+    }
+}
+
+pub struct UnalignedBamFormatter {
+    header: sam::Header,
+    tags: Vec<Tag>,
+    options: FormatOptions,
+    buffer: bam::Writer<Vec<u8>>,
+    writer: LocalUnalignedBamWriter,
+}
+
+impl UnalignedBamFormatter {
+    pub fn new(
+        writer: LocalUnalignedBamWriter,
+        tags: &[Tag],
+        options: FormatOptions,
+    ) -> UnalignedBamFormatter {
+        UnalignedBamFormatter {
+            header: sam::Header::default(),
+            tags: Vec::from(tags),
+            options,
+            buffer: bam::Writer::from(Vec::new()),
+            writer,
+        }
+    }
+
+    fn unalign(&self, mut rec: Record) -> Record {
+        let mut flags = Flags::SEGMENTED | Flags::UNMAPPED | Flags::MATE_UNMAPPED;
+        if rec.flags().is_first_segment() {
+            flags |= Flags::FIRST_SEGMENT;
+        } else {
+            flags |= Flags::LAST_SEGMENT;
+        }
+        if rec.flags().is_qc_fail() {
+            flags |= Flags::QC_FAIL;
+        }
+        if self.options.annotate_duplicates && rec.flags().is_duplicate() {
+            flags |= Flags::DUPLICATE;
+        }
+
+        let mut data = Data::default();
+        for tag in self.tags.iter() {
+            if let Some(value) = rec.data().get(tag) {
+                data.insert(*tag, value.clone());
+            }
+        }
+
+        let mut res = Record::default();
+        *res.read_name_mut() = rec.read_name_mut().take();
+        *res.flags_mut() = flags;
+        std::mem::swap(res.sequence_mut(), rec.sequence_mut());
+        std::mem::swap(res.quality_scores_mut(), rec.quality_scores_mut());
+        if let Some(bins) = &self.options.quality_bins {
+            for q in res.quality_scores_mut().as_mut().iter_mut() {
+                *q = bins.bin(*q);
+            }
+        }
+        *res.data_mut() = data;
+        res
+    }
+}
+
+impl PairFormatter for UnalignedBamFormatter {
+    fn write(&mut self, pair: (Record, Record)) -> std::io::Result<()> {
+        let (r1, r2) = orient_pair(pair);
+        let r1 = self.unalign(r1);
+        let r2 = self.unalign(r2);
+
+        self.buffer.write_record(&self.header, &r1)?;
+        self.buffer.write_record(&self.header, &r2)?;
+
+        if self.buffer.get_ref().len() > 16 * 1024 * 1024 {
+            self.writer.write(self.buffer.get_ref())?;
+            self.buffer.get_mut().clear();
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buffer.get_ref().is_empty() {
+            self.writer.write(self.buffer.get_ref())?;
+            self.buffer.get_mut().clear();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn mazab(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mazab"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// A scratch directory for one test, emptied when it starts.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mazab-cli-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write_fastq(path: &Path, seqs: &[&str]) {
+    let mut txt = String::new();
+    for (i, seq) in seqs.iter().enumerate() {
+        txt.push_str(&format!("@r{}\n{}\n+\n{}\n", i, seq, "I".repeat(seq.len())));
+    }
+    std::fs::write(path, txt).unwrap();
+}
+
+#[test]
+fn compare_two_sketches() {
+    let dir = scratch("sketches");
+    write_fastq(&dir.join("a_1.fq"), &["ACGTACGT", "TTGACCAA", "GGGCATCA"]);
+    write_fastq(&dir.join("a_2.fq"), &["CCATGACA", "AACGTTGC", "TGCATGCA"]);
+    write_fastq(&dir.join("b_1.fq"), &["ACGTACGT", "TTGACCAA"]);
+    write_fastq(&dir.join("b_2.fq"), &["CCATGACA", "AACGTTGC"]);
+    for name in ["a", "b"] {
+        let sketch = format!("{}.sk", name);
+        let r1 = format!("{}_1.fq", name);
+        let r2 = format!("{}_2.fq", name);
+        let res = mazab(&dir, &["-X", "--sketch", &sketch, &r1, &r2]);
+        assert!(res.status.success());
+    }
+    let before = (
+        std::fs::read(dir.join("a.sk")).unwrap(),
+        std::fs::read(dir.join("b.sk")).unwrap(),
+    );
+    assert!(!before.0.is_empty() && !before.1.is_empty());
+
+    let res = mazab(&dir, &["compare-sketches", "a.sk", "b.sk"]);
+    assert!(
+        res.status.success(),
+        "{}",
+        String::from_utf8_lossy(&res.stderr)
+    );
+    let out = String::from_utf8(res.stdout).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].starts_with("a.sk\tb.sk\t3\t2\tfalse\t"));
+    assert_eq!(std::fs::read(dir.join("a.sk")).unwrap(), before.0);
+    assert_eq!(std::fs::read(dir.join("b.sk")).unwrap(), before.1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn missing_bam_creates_no_outputs() {
+    let dir = scratch("missing");
+    let res = mazab(
+        &dir,
+        &[
+            "--orphans",
+            "orphans.fq",
+            "missing.bam",
+            "out_R1.fq",
+            "out_R2.fq",
+        ],
+    );
+    assert!(!res.status.success());
+    for name in ["orphans.fq", "out_R1.fq", "out_R2.fq"] {
+        assert!(!dir.join(name).exists(), "{} was created", name);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}